@@ -0,0 +1,79 @@
+#!/bin/bash
+# Example: Sending terminal escape-sequence payloads through the server
+#
+# Each payload below is a chat message that would hijack a terminal if it were
+# printed verbatim. The script sends them from one connection and prints what
+# a second connection in the same room receives. Every payload should arrive
+# with its control characters removed (payloads made only of control
+# characters are rejected with "Message is empty"). The same corpus lives in
+# src/hostile_payloads.rs, where `cargo test` checks it automatically.
+
+# Payloads are JSON string bodies, so \u001b is ESC and \\ is a backslash
+PAYLOADS=(
+    '\u001b[2J\u001b[Hclear screen and move cursor home'
+    '\u001b[1A\u001b[2Kbob: rewrite the previous line'
+    '\u001b[31mred text that never resets'
+    '\u001b[6nrequest a cursor position report'
+    '\u001b]0;pwned\u0007set the window title'
+    '\u001b]8;;https://evil.example\u001b\\innocent link\u001b]8;;\u001b\\'
+    '\u001b]52;c;ZWNobyBwd25lZA==\u0007write to the clipboard'
+    '\u001bPq#0;2;0;0;0#0~~@@vv@@~~$\u001b\\DCS sixel sequence'
+    '\u009b2J8-bit CSI clear screen'
+    '\u009d0;pwned\u009c8-bit OSC window title'
+    'hello\nbob: fake line from bob'
+    'hello\rbob: overwrite my own line'
+    'hello\u2028bob: Unicode line separator'
+    '\u202eexe.txt\u202c right-to-left override'
+    '\u2066isolate\u2069 bidi isolate'
+    '\u061carabic letter mark\u200f'
+    '\u0007\u0007ring the bell'
+    '\u0000null byte'
+    '\u0008\u0008\u0008backspace over text'
+    '\u001b\u0007\u009b'
+)
+
+echo "=== Rust Chat Hostile Payload Demo ==="
+echo
+
+echo "Building the server..."
+cargo build --bin server || exit 1
+
+echo "Starting server..."
+cargo run --bin server &
+SERVER_PID=$!
+sleep 2
+
+exec 3<>/dev/tcp/127.0.0.1/8080
+exec 4<>/dev/tcp/127.0.0.1/8080
+
+# Print what arrives on a connection within a short window
+drain() {
+    while read -r -t 0.3 -u "$1" line; do
+        printf '%s\n' "$line" | cat -v
+    done
+}
+
+echo '{"CreateRoom":{"room_name":"Payload Test","max_users":2}}' >&3
+sleep 0.3
+ROOM_ID=$(drain 3 | sed -n 's/.*"room_id":"\([^"]*\)".*/\1/p')
+
+echo "{\"JoinRoom\":{\"room_id\":\"$ROOM_ID\",\"username\":\"mallory\"}}" >&3
+echo "{\"JoinRoom\":{\"room_id\":\"$ROOM_ID\",\"username\":\"alice\"}}" >&4
+sleep 0.3
+drain 3 >/dev/null
+drain 4 >/dev/null
+
+for payload in "${PAYLOADS[@]}"; do
+    echo
+    echo "Sent:     $payload"
+    echo "{\"Chat\":{\"content\":\"$payload\"}}" >&3
+    sleep 0.2
+    echo "Received: $(drain 4)"
+    # Errors (such as "Message is empty") go back to the sender
+    drain 3 | grep '"Error"' | sed 's/^/Sender:   /'
+done
+
+exec 3>&- 4>&-
+kill $SERVER_PID 2>/dev/null
+echo
+echo "Demo stopped."
@@ -0,0 +1,84 @@
+// ============================================================================
+// Account Storage
+// ============================================================================
+//
+// Registered accounts live in a local SQLite file. Passwords are only ever
+// stored as Argon2id PHC strings, which carry their own salt and parameters.
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rusqlite::{params, Connection, OptionalExtension};
+
+pub const MIN_PASSWORD_LEN: usize = 8;
+pub const MAX_PASSWORD_LEN: usize = 128;
+
+type StoreResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+pub struct AccountStore {
+    conn: Connection,
+}
+
+impl AccountStore {
+    pub fn open(path: &str) -> StoreResult<Self> {
+        let conn = Connection::open(path)?;
+        // `name_key` is the lowercased username so "Alice" and "alice" can't
+        // both be registered
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS accounts (
+                name_key      TEXT PRIMARY KEY,
+                username      TEXT NOT NULL,
+                password_hash TEXT NOT NULL,
+                created_at    INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+            );",
+        )?;
+        Ok(Self { conn })
+    }
+
+    /// Creates an account. Returns `false` if the username is already registered.
+    pub fn register(&self, username: &str, password: &str) -> StoreResult<bool> {
+        if self.find(username)?.is_some() {
+            return Ok(false);
+        }
+
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| e.to_string())?
+            .to_string();
+
+        let inserted = self.conn.execute(
+            "INSERT OR IGNORE INTO accounts (name_key, username, password_hash) VALUES (?1, ?2, ?3)",
+            params![username.to_lowercase(), username, hash],
+        )?;
+        Ok(inserted == 1)
+    }
+
+    /// Checks a password, returning the account's canonical username on success.
+    pub fn verify(&self, username: &str, password: &str) -> StoreResult<Option<String>> {
+        let Some((canonical, stored_hash)) = self.find(username)? else {
+            return Ok(None);
+        };
+
+        let parsed = PasswordHash::new(&stored_hash).map_err(|e| e.to_string())?;
+        let valid = Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok();
+        Ok(valid.then_some(canonical))
+    }
+
+    pub fn exists(&self, username: &str) -> StoreResult<bool> {
+        Ok(self.find(username)?.is_some())
+    }
+
+    fn find(&self, username: &str) -> StoreResult<Option<(String, String)>> {
+        let account = self.conn
+            .query_row(
+                "SELECT username, password_hash FROM accounts WHERE name_key = ?1",
+                params![username.to_lowercase()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        Ok(account)
+    }
+}
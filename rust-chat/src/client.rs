@@ -1,30 +1,567 @@
-use std::io::{self, Write};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, IsTerminal, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Local, NaiveTime, SecondsFormat, TimeDelta, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+#[cfg(test)]
+mod hostile_payloads;
 
 // ============================================================================
 // Message Types
 // ============================================================================
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(clippy::enum_variant_names)]
 enum Message {
     // Client -> Server
-    CreateRoom { room_name: String, max_users: usize },
-    JoinRoom { room_id: String, username: String },
-    Chat { content: String },
+    CreateRoom {
+        room_name: String,
+        max_users: usize,
+        max_spectators: usize,
+        announcement_only: bool,
+        require_login: bool,
+        history_size: usize,
+        history_max_age_secs: u64,
+    },
+    JoinRoom {
+        room_id: String,
+        username: String,
+        #[serde(default)]
+        wait: bool,
+        #[serde(default)]
+        spectate: bool,
+    },
+    LeaveRoom,
+    Chat { content: String, reply_to: Option<String>, ttl_seconds: Option<u64> },
     GetRoomInfo,
+    SetMaxSpectators { max_spectators: usize },
+    SetAnnouncementOnly { enabled: bool },
+    SetModerator { username: String, enabled: bool },
+    SetPoster { username: String, enabled: bool },
+    Register { username: String, password: String },
+    Login { username: String, password: String },
+    Resume { token: String },
+    ChangeNick { username: String },
+    SetPresence {
+        presence: Presence,
+        #[serde(default)]
+        status: Option<String>,
+    },
+    Typing { active: bool },
+    DirectMessage { to: String, content: String },
+    GetHistory { room_id: String, before: Option<u64>, limit: usize },
+    SearchHistory { room_id: String, query: String, limit: usize },
+    GetThread { room_id: String, message_id: String },
+    EditMessage { message_id: String, content: String },
+    DeleteMessage { message_id: String },
+    React { message_id: String, emoji: String },
+    Unreact { message_id: String, emoji: String },
+    PinMessage { message_id: String },
+    UnpinMessage { message_id: String },
+    CreatePoll { question: String, options: Vec<String>, duration_secs: Option<u64> },
+    Vote { poll_id: u64, option: usize },
+    ClosePoll { poll_id: u64 },
+    ScheduleMessage { content: String, send_at: String },
+    GetScheduledMessages,
+    CancelScheduledMessage { id: u64 },
 
     // Server -> Client
     Connected,
-    RoomCreated { room_name: String, room_id: String, max_users: usize },
+    LoggedIn { username: String },
+    RoomCreated { room_name: String, room_id: String, max_users: usize, max_spectators: usize },
     JoinedRoom { room_name: String, username: String },
-    UserMessage { username: String, content: String },
-    RoomInfo { room_name: String, users: Vec<String>, current_count: usize, max_users: usize },
+    ResumeToken { token: String, grace_period_secs: u64 },
+    Resumed { room_name: String, username: String },
+    Waitlisted { room_name: String, position: usize },
+    UserMessage {
+        #[serde(default)]
+        id: String,
+        #[serde(default)]
+        seq: u64,
+        #[serde(default)]
+        timestamp: String,
+        username: String,
+        content: String,
+        #[serde(default)]
+        history: bool,
+        #[serde(default)]
+        edited_at: Option<String>,
+        #[serde(default)]
+        reply_to: Option<Quote>,
+        #[serde(default)]
+        reactions: BTreeMap<String, usize>,
+        #[serde(default)]
+        mentions: Vec<String>,
+        #[serde(default)]
+        mentioned: bool,
+        #[serde(default)]
+        expires_at: Option<String>,
+    },
+    RoomInfo {
+        room_name: String,
+        users: Vec<RoomUser>,
+        current_count: usize,
+        max_users: usize,
+        #[serde(default)]
+        spectators: Vec<String>,
+        #[serde(default)]
+        max_spectators: usize,
+        #[serde(default)]
+        announcement_only: bool,
+        #[serde(default)]
+        moderators: Vec<String>,
+        #[serde(default)]
+        posters: Vec<String>,
+        #[serde(default)]
+        require_login: bool,
+        #[serde(default)]
+        pinned: Vec<HistoryEntry>,
+    },
+    MaxSpectatorsSet { max_spectators: usize },
+    PermissionsUpdated { announcement_only: bool, moderators: Vec<String>, posters: Vec<String> },
+    NickChanged { old: String, new: String },
+    PresenceChanged { username: String, presence: Presence, status: Option<String> },
+    UserTyping { username: String, active: bool },
+    PrivateMessage { from: String, to: String, content: String },
+    History { room_id: String, messages: Vec<HistoryEntry> },
+    SearchResults { room_id: String, query: String, messages: Vec<HistoryEntry> },
+    Thread { room_id: String, root_id: String, messages: Vec<HistoryEntry> },
+    MessageEdited { id: String, seq: u64, content: String, edited_at: String, by: String },
+    MessageDeleted { id: String, seq: u64, by: String },
+    MessageExpired { id: String, seq: u64 },
+    ReactionsUpdated { message_id: String, seq: u64, reactions: BTreeMap<String, usize> },
+    PinnedMessages { room_id: String, messages: Vec<HistoryEntry> },
+    MessagePinned { message: HistoryEntry, by: String },
+    MessageUnpinned { id: String, seq: u64, by: String },
+    PollStarted { poll: PollSummary },
+    PollUpdated { poll: PollSummary },
+    PollClosed { poll: PollSummary, by: Option<String> },
+    OpenPolls { room_id: String, polls: Vec<PollSummary> },
+    MessageScheduled { message: ScheduledSummary },
+    ScheduledMessages { room_id: String, messages: Vec<ScheduledSummary> },
+    ScheduledMessageCancelled { id: u64 },
     UserLeft { username: String },
-    Error { message: String },
+    Error {
+        message: String,
+        #[serde(default)]
+        kind: ErrorKind,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Presence {
+    #[default]
+    Online,
+    Away,
+    Busy,
+    Dnd,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RoomUser {
+    username: String,
+    #[serde(default)]
+    presence: Presence,
+    #[serde(default)]
+    status: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HistoryEntry {
+    id: String,
+    seq: u64,
+    timestamp: String,
+    username: String,
+    content: String,
+    #[serde(default)]
+    edited_at: Option<String>,
+    #[serde(default)]
+    reply_to: Option<Quote>,
+    #[serde(default)]
+    reactions: BTreeMap<String, usize>,
+}
+
+/// The message a reply answers. `snippet` is `None` once it's deleted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Quote {
+    id: String,
+    seq: u64,
+    username: String,
+    snippet: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PollSummary {
+    id: u64,
+    question: String,
+    options: Vec<String>,
+    votes: Vec<usize>,
+    created_by: String,
+    #[serde(default)]
+    closes_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ScheduledSummary {
+    id: u64,
+    content: String,
+    send_at: String,
+}
+
+/// Messages requested per `/history` page.
+const HISTORY_PAGE_SIZE: usize = 20;
+
+/// Matches requested per `/search`.
+const SEARCH_RESULT_LIMIT: usize = 20;
+
+/// Deepest a reply is indented in `/thread`.
+const MAX_THREAD_INDENT: usize = 6;
+
+/// Mentions kept for `/mentions`; older ones are dropped.
+const MAX_MENTIONS: usize = 50;
+
+/// Lines kept for redrawing the screen when a message changes.
+const MAX_SHOWN_LINES: usize = 200;
+
+/// Longest quote of a parent message shown above a reply, in characters.
+/// Matches what the server keeps.
+const SNIPPET_LEN: usize = 80;
+
+/// Latest resume token from the server, presented after a reconnect to get
+/// our seat back.
+type ResumeToken = Arc<std::sync::Mutex<Option<String>>>;
+
+/// Server IDs of the messages we've seen in the current room, by sequence
+/// number, so commands can refer to a message by the number shown next to it.
+type MessageIds = Arc<std::sync::Mutex<HashMap<u64, String>>>;
+
+/// Messages in the current room that mentioned us, oldest first.
+type Mentions = Arc<std::sync::Mutex<VecDeque<HistoryEntry>>>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+enum ErrorKind {
+    #[default]
+    Other,
+    PermissionDenied,
+    UsernameTaken,
+    InvalidUsername,
+    InvalidRoomName,
+    InvalidMessage,
+    AuthenticationRequired,
+    AuthenticationFailed,
+    RegistrationFailed,
+    ResumeFailed,
+    UserNotFound,
+    MessageNotFound,
+    PollNotFound,
+}
+
+// ============================================================================
+// Presence
+// ============================================================================
+
+/// Environment variable overriding how long before an idle user is marked
+/// away, in seconds. 0 turns automatic away off.
+const IDLE_AWAY_ENV: &str = "RUST_CHAT_IDLE_AWAY_SECS";
+const DEFAULT_IDLE_AWAY_SECS: u64 = 300;
+
+/// The presence the user picked with /status, plus what's needed to mark
+/// them away automatically while they're idle in a room.
+struct PresenceState {
+    presence: Presence,
+    status: Option<String>,
+    last_input: Instant,
+    /// Set while we've switched an online user to away for being idle.
+    auto_away: bool,
+    idle_after: Option<Duration>,
+}
+
+type SharedPresence = Arc<std::sync::Mutex<PresenceState>>;
+
+fn idle_away_after() -> Option<Duration> {
+    let secs = std::env::var(IDLE_AWAY_ENV)
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(DEFAULT_IDLE_AWAY_SECS);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+fn parse_presence(value: &str) -> Option<Presence> {
+    match value.to_lowercase().as_str() {
+        "online" => Some(Presence::Online),
+        "away" => Some(Presence::Away),
+        "busy" => Some(Presence::Busy),
+        "dnd" => Some(Presence::Dnd),
+        _ => None,
+    }
+}
+
+fn describe_presence(presence: Presence, status: Option<&str>) -> String {
+    let label = match presence {
+        Presence::Online => "online",
+        Presence::Away => "away",
+        Presence::Busy => "busy",
+        Presence::Dnd => "do not disturb",
+    };
+    match status {
+        Some(status) => format!("{}: {}", label, status),
+        None => label.to_string(),
+    }
+}
+
+/// Records input from the user, bringing them back if they were marked away
+/// automatically.
+async fn note_activity(
+    tx: &mpsc::Sender<String>,
+    presence: &SharedPresence,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let restore = {
+        let mut state = presence.lock().unwrap();
+        state.last_input = Instant::now();
+        std::mem::take(&mut state.auto_away).then(|| state.status.clone())
+    };
+
+    if let Some(status) = restore {
+        let msg = Message::SetPresence { presence: Presence::Online, status };
+        tx.send(serde_json::to_string(&msg)?).await?;
+    }
+    Ok(())
+}
+
+/// Marks the user away once they've been idle for `idle_after`. Users who
+/// chose a presence other than online are left alone.
+async fn auto_away(tx: mpsc::Sender<String>, presence: SharedPresence, idle_after: Duration) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let status = {
+            let mut state = presence.lock().unwrap();
+            if state.auto_away
+                || state.presence != Presence::Online
+                || state.last_input.elapsed() < idle_after
+            {
+                continue;
+            }
+            state.auto_away = true;
+            state.status.clone()
+        };
+
+        let msg = Message::SetPresence { presence: Presence::Away, status };
+        let Ok(json) = serde_json::to_string(&msg) else {
+            continue;
+        };
+        if tx.send(json).await.is_err() {
+            break;
+        }
+    }
+}
+
+// ============================================================================
+// Typing Indicators
+// ============================================================================
+
+/// How often we repeat our own "typing" notice while the user keeps typing.
+const TYPING_NOTICE_INTERVAL: Duration = Duration::from_secs(3);
+/// How long someone counts as typing after their last notice.
+const TYPING_DISPLAY_TIME: Duration = Duration::from_secs(6);
+
+fn send_typing(tx: &mpsc::Sender<String>, active: bool) {
+    // Typing notices are best effort, so never wait for room in the queue
+    if let Ok(json) = serde_json::to_string(&Message::Typing { active }) {
+        let _ = tx.try_send(json);
+    }
+}
+
+/// Whether the user has started typing a chat line. The typing status line
+/// shares the input line, so it's only drawn while this is false. Holding the
+/// lock while drawing keeps the status and the first typed key from landing
+/// on the line together.
+static COMPOSING: std::sync::Mutex<bool> = std::sync::Mutex::new(false);
+
+/// Other users currently typing, shown on one status line below the chat that
+/// is rewritten in place and cleared once nobody is typing.
+#[derive(Default)]
+struct TypingStatus {
+    /// When we last heard from each user; entries expire after
+    /// `TYPING_DISPLAY_TIME`.
+    typing: HashMap<String, Instant>,
+    /// Whether the status line is on screen.
+    visible: bool,
+}
+
+impl TypingStatus {
+    fn update(&mut self, username: String, active: bool) {
+        if active {
+            self.typing.insert(username, Instant::now());
+        } else {
+            self.typing.remove(&username);
+        }
+    }
+
+    fn remove(&mut self, username: &str) {
+        self.typing.remove(username);
+    }
+
+    /// When the next entry runs out, if anyone is typing.
+    fn next_expiry(&self) -> Option<Instant> {
+        self.typing.values().min().map(|since| *since + TYPING_DISPLAY_TIME)
+    }
+
+    /// Erases the status line so other output can take its place. If the
+    /// user has started typing, they have already written over it.
+    fn hide(&mut self) {
+        let composing = COMPOSING.lock().unwrap();
+        if std::mem::take(&mut self.visible) && !*composing {
+            print!("\r\x1b[2K");
+            io::stdout().flush().unwrap_or_default();
+        }
+    }
+
+    /// Drops expired entries and draws the status line, or erases it if
+    /// nobody is typing. Nothing is drawn while the user is typing a line or
+    /// when stdout isn't a terminal.
+    fn show(&mut self) {
+        self.typing.retain(|_, since| since.elapsed() < TYPING_DISPLAY_TIME);
+
+        let composing = COMPOSING.lock().unwrap();
+        if *composing || !io::stdout().is_terminal() {
+            self.visible = false;
+            return;
+        }
+
+        let mut names: Vec<&str> = self.typing.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        let status = match names.as_slice() {
+            [] if !self.visible => return,
+            [] => String::new(),
+            [name] => format!("{} is typing\u{2026}", name),
+            [rest @ .., last] => format!("{} and {} are typing\u{2026}", rest.join(", "), last),
+        };
+        print!("\r\x1b[2K{}", status);
+        io::stdout().flush().unwrap_or_default();
+        self.visible = !status.is_empty();
+    }
+}
+
+/// What's on screen in the room, oldest first. A terminal can't take back a
+/// printed line, so when a message is edited, deleted or expires the screen
+/// is redrawn from these.
+#[derive(Default)]
+struct Transcript {
+    lines: VecDeque<ShownLine>,
+}
+
+enum ShownLine {
+    Message(Box<ShownMessage>),
+    /// Anything else worth keeping on screen, such as a join or a private
+    /// message.
+    Notice(String),
+}
+
+struct ShownMessage {
+    message: HistoryEntry,
+    expires_at: Option<String>,
+}
+
+impl ShownMessage {
+    fn format(&self, message_ids: &MessageIds) -> String {
+        let mut line = format_entry(&self.message, message_ids);
+        if let Some(expires_at) = &self.expires_at {
+            line.push_str(&format!(" (disappears in {})", time_until(expires_at)));
+        }
+        line
+    }
+}
+
+impl ShownLine {
+    fn format(&self, message_ids: &MessageIds) -> String {
+        match self {
+            ShownLine::Message(shown) => shown.format(message_ids),
+            ShownLine::Notice(text) => text.clone(),
+        }
+    }
+}
+
+impl Transcript {
+    fn push(&mut self, shown: ShownMessage) {
+        self.push_line(ShownLine::Message(Box::new(shown)));
+    }
+
+    /// Prints a notice and keeps it for redrawing.
+    fn notice(&mut self, text: String) {
+        println!("\n{}", text);
+        self.push_line(ShownLine::Notice(text));
+    }
+
+    fn push_line(&mut self, line: ShownLine) {
+        self.lines.push_back(line);
+        if self.lines.len() > MAX_SHOWN_LINES {
+            self.lines.pop_front();
+        }
+    }
+
+    fn messages_mut(&mut self) -> impl Iterator<Item = &mut HistoryEntry> {
+        self.lines.iter_mut().filter_map(|line| match line {
+            ShownLine::Message(shown) => Some(&mut shown.message),
+            ShownLine::Notice(_) => None,
+        })
+    }
+
+    fn clear(&mut self) {
+        self.lines.clear();
+    }
+
+    /// Applies an edit to the message and to the quotes of its replies.
+    /// Returns whether any of them are on screen.
+    fn edit(&mut self, seq: u64, content: &str, edited_at: &str) -> bool {
+        let mut changed = false;
+        for message in self.messages_mut() {
+            if message.seq == seq {
+                message.content = content.to_string();
+                message.edited_at = Some(edited_at.to_string());
+                changed = true;
+            } else if let Some(quote) = message.reply_to.as_mut().filter(|quote| quote.seq == seq) {
+                quote.snippet = Some(snippet(content));
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Drops the message and blanks the quotes of its replies. Returns
+    /// whether any of them were on screen.
+    fn remove(&mut self, seq: u64) -> bool {
+        let before = self.lines.len();
+        self.lines.retain(|line| !matches!(line, ShownLine::Message(shown) if shown.message.seq == seq));
+        let mut changed = self.lines.len() < before;
+        for quote in self.messages_mut().filter_map(|message| message.reply_to.as_mut()) {
+            if quote.seq == seq && quote.snippet.take().is_some() {
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Clears the screen and prints everything again. Does nothing when
+    /// stdout isn't a terminal, since the old lines can't be taken back.
+    fn redraw(&self, message_ids: &MessageIds) {
+        if !io::stdout().is_terminal() {
+            return;
+        }
+        clear_terminal();
+        for line in &self.lines {
+            println!("{}", line.format(message_ids));
+        }
+    }
 }
 
 // ============================================================================
@@ -41,14 +578,271 @@ fn clear_terminal() {
     }
 }
 
+/// Formats a server timestamp as a local `[HH:MM] ` prefix, or nothing if
+/// it's missing or malformed.
+fn format_time(timestamp: &str) -> String {
+    DateTime::parse_from_rfc3339(timestamp)
+        .map(|time| time.with_timezone(&Local).format("[%H:%M] ").to_string())
+        .unwrap_or_default()
+}
+
+/// Rings the terminal bell and shows `line` in reverse video, to draw the
+/// eye to a message that mentions us. Left plain when output isn't a terminal.
+fn highlight(line: &str) -> String {
+    if io::stdout().is_terminal() {
+        format!("\x07\x1b[7m{}\x1b[0m", line)
+    } else {
+        line.to_string()
+    }
+}
+
+/// Formats how long until a server timestamp as `45s`, `3m` or `2h`,
+/// rounding up.
+fn time_until(timestamp: &str) -> String {
+    let Ok(time) = DateTime::parse_from_rfc3339(timestamp) else {
+        return "a moment".to_string();
+    };
+    let millis = (time.with_timezone(&Utc) - Utc::now()).num_milliseconds();
+    let secs = u64::try_from(millis).unwrap_or(0).div_ceil(1000).max(1);
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs.div_ceil(60)),
+        _ => format!("{}h", secs.div_ceil(3600)),
+    }
+}
+
+/// Parses a duration like `90`, `90s`, `5m` or `2h`; bare numbers are seconds.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s"),
+    };
+    let number = number.parse::<u64>().ok()?;
+    let secs = match unit {
+        "s" => number,
+        "m" => number.checked_mul(60)?,
+        "h" => number.checked_mul(60 * 60)?,
+        _ => return None,
+    };
+    Some(Duration::from_secs(secs))
+}
+
+/// The next time the local clock reads `HH:MM`: later today, or tomorrow if
+/// that time has passed.
+fn next_local_time(value: &str) -> Option<DateTime<Utc>> {
+    next_time_after(value, Local::now())
+}
+
+/// The first time after `now` that its clock reads `HH:MM`.
+fn next_time_after<Tz: TimeZone>(value: &str, now: DateTime<Tz>) -> Option<DateTime<Utc>> {
+    let time = NaiveTime::parse_from_str(value, "%H:%M").ok()?;
+    let today = now.date_naive().and_time(time).and_local_timezone(now.timezone()).earliest()?;
+    let next = if today > now { today } else { today + TimeDelta::days(1) };
+    Some(next.with_timezone(&Utc))
+}
+
+/// Formats a server timestamp as a local `Tue 09:00`.
+fn format_day_time(timestamp: &str) -> String {
+    DateTime::parse_from_rfc3339(timestamp)
+        .map(|time| time.with_timezone(&Local).format("%a %H:%M").to_string())
+        .unwrap_or_else(|_| timestamp.to_string())
+}
+
+/// Splits command arguments on spaces, keeping "quoted phrases" together.
+/// Returns `None` if a quote is left open.
+fn split_quoted(arguments: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+    let mut started = false;
+    for c in arguments.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                started = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if started {
+                    words.push(std::mem::take(&mut word));
+                    started = false;
+                }
+            }
+            c => {
+                word.push(c);
+                started = true;
+            }
+        }
+    }
+    if quoted {
+        return None;
+    }
+    if started {
+        words.push(word);
+    }
+    Some(words)
+}
+
+/// Makes text from the server safe to print. Control and bidirectional
+/// override characters are shown as visible escapes (e.g. `\u{1b}`) instead of
+/// being interpreted by the terminal.
+fn escape_terminal(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            let bidi = matches!(c, '\u{061C}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}');
+            if c.is_control() || bidi || c == '\u{2028}' || c == '\u{2029}' {
+                c.escape_unicode().to_string()
+            } else {
+                c.to_string()
+            }
+        })
+        .collect()
+}
+
+/// Applies `escape_terminal` to every string in a decoded server message,
+/// including map keys such as reaction emoji, so no field can reach the
+/// terminal unescaped whatever the server sends.
+fn escape_strings(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::String(text) => *text = escape_terminal(text),
+        serde_json::Value::Array(items) => items.iter_mut().for_each(escape_strings),
+        serde_json::Value::Object(fields) => {
+            *fields = std::mem::take(fields)
+                .into_iter()
+                .map(|(key, mut field)| {
+                    escape_strings(&mut field);
+                    (escape_terminal(&key), field)
+                })
+                .collect();
+        }
+        _ => {}
+    }
+}
+
 fn show_help() {
     println!("\n=== Chat Commands ===");
     println!("/help   - Show this help message");
     println!("/count  - Show who is in the room");
+    println!("/spectators <n> - Set the spectator limit, 0 disables (owner only)");
+    println!("/announce on|off - Restrict posting to moderators and approved posters");
+    println!("/mod <user>      - Make a user a moderator (owner only)");
+    println!("/unmod <user>    - Remove a moderator (owner only)");
+    println!("/allow <user>    - Let a user post in an announcement room");
+    println!("/disallow <user> - Revoke a user's posting permission");
+    println!("/nick <name>     - Change your nickname");
+    println!("/msg <user> <text> - Send a private message");
+    println!("/later <time> <text> - Send a message after a delay, e.g. 30m or 2h");
+    println!("/at <HH:MM> <text> - Send a message at the next HH:MM on your clock");
+    println!("/scheduled - List your scheduled messages");
+    println!("/cancel <id> - Cancel a scheduled message");
+    println!("/ephemeral <time> <text> - Send a message that disappears after e.g. 30s, 5m or 1h");
+    println!("/history [before] - Show older messages, before message #<before> if given");
+    println!("/search <terms> - Find messages in this room's history containing every term");
+    println!("/reply <number> <text> - Reply to a message, quoting it");
+    println!("/thread <number> - Show the conversation a message belongs to");
+    println!("/mentions - List recent messages that mentioned you");
+    println!("/react <number> <emoji> - React to a message instead of replying with \"+1\"");
+    println!("/unreact <number> <emoji> - Take back a reaction");
+    println!("/edit <number> <text> - Replace the text of a message (yours, or any if you moderate)");
+    println!("/delete <number> - Delete a message (yours, or any if you moderate)");
+    println!("/poll [time] \"question\" <option> <option> ... - Start a poll, closing after [time] if given");
+    println!("/vote <poll> <option> - Vote in a poll, or change your vote");
+    println!("/closepoll <poll> - Close a poll and show the result (its creator or a moderator)");
+    println!("/pin <number>    - Pin a message for everyone in the room (moderators)");
+    println!("/unpin <number>  - Unpin a message (moderators)");
+    println!("/status <online|away|busy|dnd> [text] - Set your presence and status line");
     println!("/leave  - Leave the room and return to main menu");
     println!("=====================\n");
 }
 
+/// Switches the terminal between normal line input and reading one key at a
+/// time without echo, which lets the chat loop notice typing before Enter.
+/// Returns `false` if the terminal can't be switched.
+fn set_key_input(enabled: bool) -> bool {
+    let args: &[&str] = if enabled { &["-icanon", "-echo"] } else { &["icanon", "echo"] };
+    std::process::Command::new("stty")
+        .args(args)
+        .stdin(std::process::Stdio::inherit())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Reads a chat line key by key, sending typing notices as the user types.
+/// Falls back to plain line input when stdin isn't an interactive terminal.
+fn read_chat_line(tx: &mpsc::Sender<String>) -> String {
+    if !io::stdin().is_terminal() || !set_key_input(true) {
+        return read_line();
+    }
+
+    let mut line = String::new();
+    let mut pending = Vec::new();
+    let mut in_escape = false;
+    let mut last_notice: Option<Instant> = None;
+    let mut stdin = io::stdin().lock();
+    let mut byte = [0u8; 1];
+
+    while let Ok(1) = stdin.read(&mut byte) {
+        match byte[0] {
+            b'\n' | b'\r' => {
+                println!();
+                break;
+            }
+            // Swallow escape sequences such as arrow keys
+            0x1b => in_escape = true,
+            b if in_escape => in_escape = !(b.is_ascii_alphabetic() || b == b'~'),
+            0x7f | 0x08 => {
+                if line.pop().is_some() {
+                    print!("\x08 \x08");
+                }
+                if line.is_empty() {
+                    *COMPOSING.lock().unwrap() = false;
+                    if last_notice.take().is_some() {
+                        send_typing(tx, false);
+                    }
+                }
+            }
+            b if b < 0x20 => {}
+            b => {
+                // Multi-byte characters arrive one byte at a time
+                pending.push(b);
+                let Ok(text) = std::str::from_utf8(&pending) else {
+                    if pending.len() >= 4 {
+                        pending.clear();
+                    }
+                    continue;
+                };
+                if line.is_empty() {
+                    // Take over the line from the typing status, if it's showing
+                    let mut composing = COMPOSING.lock().unwrap();
+                    *composing = true;
+                    print!("\r\x1b[2K{}", text);
+                } else {
+                    print!("{}", text);
+                }
+                line.push_str(text);
+                pending.clear();
+
+                if !line.starts_with('/')
+                    && !matches!(last_notice, Some(sent) if sent.elapsed() < TYPING_NOTICE_INTERVAL)
+                {
+                    send_typing(tx, true);
+                    last_notice = Some(Instant::now());
+                }
+            }
+        }
+        io::stdout().flush().unwrap_or_default();
+    }
+
+    set_key_input(false);
+    *COMPOSING.lock().unwrap() = false;
+
+    // A sent message ends the indicator by itself; anything else needs a stop notice
+    let line = line.trim().to_string();
+    if last_notice.is_some() && (line.is_empty() || line.starts_with('/')) {
+        send_typing(tx, false);
+    }
+    line
+}
+
 fn read_line() -> String {
     let mut input = String::new();
     io::stdin().read_line(&mut input).unwrap_or_default();
@@ -72,64 +866,112 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Connected to chat server at {}", addr);
 
     let (tx, rx) = mpsc::channel::<String>(100);
-    let (reader, writer) = stream.into_split();
-    let reader = BufReader::new(reader);
+    let resume_token: ResumeToken = Arc::new(std::sync::Mutex::new(None));
+    let message_ids: MessageIds = Arc::new(std::sync::Mutex::new(HashMap::new()));
+    let mentions: Mentions = Arc::new(std::sync::Mutex::new(VecDeque::new()));
+    let presence: SharedPresence = Arc::new(std::sync::Mutex::new(PresenceState {
+        presence: Presence::Online,
+        status: None,
+        last_input: Instant::now(),
+        auto_away: false,
+        idle_after: idle_away_after(),
+    }));
 
     // Channels for coordination
-    let (menu_tx, mut menu_rx) = mpsc::channel::<bool>(1);
-    let (join_tx, mut join_rx) = mpsc::channel::<bool>(1);
+    let (menu_tx, menu_rx) = mpsc::channel::<bool>(1);
+    let (join_tx, join_rx) = mpsc::channel::<bool>(1);
     let (room_id_tx, mut room_id_rx) = mpsc::channel::<String>(1);
+    let (waitlist_tx, waitlist_rx) = mpsc::channel::<usize>(1);
+    let (username_tx, username_rx) = mpsc::channel::<bool>(1);
+    let (auth_tx, mut auth_rx) = mpsc::channel::<Option<String>>(1);
+    // Roomy enough for a room's whole history replay
+    let (history_tx, history_rx) = mpsc::channel::<Backlog>(256);
+    let joining = Arc::new(AtomicBool::new(false));
+    let mut signals = JoinSignals { joining: Arc::clone(&joining), menu_rx, join_rx, waitlist_rx, username_rx, history_rx };
+    let senders = SignalSenders {
+        joining,
+        room_id_tx,
+        menu_tx,
+        join_tx,
+        waitlist_tx,
+        username_tx,
+        auth_tx,
+        history_tx,
+    };
+
+    // Ctrl+C could land while the chat loop has echo turned off
+    if io::stdin().is_terminal() {
+        tokio::spawn(async {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                set_key_input(false);
+                std::process::exit(130);
+            }
+        });
+    }
 
-    // Spawn incoming message handler
-    let tx_clone = tx.clone();
-    tokio::spawn(async move {
-        handle_incoming(reader, tx_clone, room_id_tx, menu_tx, join_tx).await;
-    });
+    // Spawn connection handler, which runs the incoming and outgoing handlers
+    tokio::spawn(maintain_connection(
+        addr,
+        stream,
+        rx,
+        senders,
+        Arc::clone(&resume_token),
+        Arc::clone(&message_ids),
+        Arc::clone(&mentions),
+    ));
 
-    // Spawn outgoing message handler
-    tokio::spawn(async move {
-        handle_outgoing(writer, rx).await;
-    });
+    // Account this connection is logged in as, if any
+    let mut account: Option<String> = None;
 
     // Main menu loop
     loop {
         println!("\n=== Welcome to Rust Chat ===");
+        if let Some(account) = &account {
+            println!("Logged in as {}", account);
+        }
         println!("1. Create a new chat room");
         println!("2. Join an existing chat room (requires room ID)");
-        println!("3. Exit");
+        println!("3. Log in");
+        println!("4. Register an account");
+        println!("5. Exit");
 
-        let choice = prompt("Enter your choice (1, 2, or 3): ");
+        let choice = prompt("Enter your choice (1-5): ");
 
         match choice.as_str() {
             "1" => {
-                if let Some(room_id) = create_room(&tx, &mut room_id_rx).await? {
-                    let username = prompt("Enter your username: ");
-                    join_room_by_id(&tx, &room_id, &username).await?;
-                    tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
-                    clear_terminal();
-                    chat_loop(&tx).await?;
+                if let Some(room_id) = create_room(&tx, &mut room_id_rx, account.is_some()).await? {
+                    let username = account.clone().unwrap_or_else(|| prompt("Enter your username: "));
+                    if join_room(&tx, &room_id, username, false, false, &mut signals).await? {
+                        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+                        clear_terminal();
+                        chat_loop(&tx, &resume_token, &presence, &message_ids, &mentions, &mut signals.history_rx, &room_id).await?;
+                    }
                 }
             }
             "2" => {
                 let room_id = prompt("Enter room ID (UUID): ");
-                let username = prompt("Enter your username: ");
-                join_room_by_id(&tx, &room_id, &username).await?;
+                let username = account.clone().unwrap_or_else(|| prompt("Enter your username: "));
+                let spectate = prompt("Join as a read-only spectator? (y/n): ")
+                    .eq_ignore_ascii_case("y");
+                let wait = !spectate
+                    && prompt("Wait in line if the room is full? (y/n): ").eq_ignore_ascii_case("y");
 
-                // Wait for join result
-                tokio::select! {
-                    Some(_) = menu_rx.recv() => continue,
-                    Some(_) = join_rx.recv() => {
-                        tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
-                        clear_terminal();
-                        chat_loop(&tx).await?;
-                    }
-                    _ = tokio::time::sleep(tokio::time::Duration::from_secs(2)) => {
-                        println!("\nConnection timeout. Returning to menu...");
-                        continue;
-                    }
+                if !join_room(&tx, &room_id, username, wait, spectate, &mut signals).await? {
+                    continue;
+                }
+
+                tokio::time::sleep(tokio::time::Duration::from_millis(300)).await;
+                clear_terminal();
+                chat_loop(&tx, &resume_token, &presence, &message_ids, &mentions, &mut signals.history_rx, &room_id).await?;
+            }
+            "3" | "4" => {
+                let register = choice == "4";
+                if let Some(name) = authenticate(&tx, register, &mut auth_rx).await? {
+                    account = Some(name);
                 }
+                prompt("Press Enter to continue...");
             }
-            "3" => {
+            "5" => {
                 println!("Goodbye!");
                 return Ok(());
             }
@@ -149,6 +991,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 async fn create_room(
     tx: &mpsc::Sender<String>,
     room_id_rx: &mut mpsc::Receiver<String>,
+    logged_in: bool,
 ) -> Result<Option<String>, Box<dyn std::error::Error>> {
     let room_name = prompt("Enter room name: ");
 
@@ -159,7 +1002,38 @@ async fn create_room(
         println!("Minimum is 2 users. Setting to 2.");
     }
 
-    let message = Message::CreateRoom { room_name, max_users };
+    let max_spectators = prompt("Enter maximum number of spectators (0 to disable, default 10): ")
+        .parse::<usize>()
+        .unwrap_or(10);
+
+    let history_size = prompt("Recent messages to show new arrivals (0 to disable, default 50): ")
+        .parse::<usize>()
+        .unwrap_or(50);
+    let history_max_age_secs = if history_size > 0 {
+        prompt("Minutes to keep recent messages (0 for no limit, default 60): ")
+            .parse::<u64>()
+            .unwrap_or(60)
+            .saturating_mul(60)
+    } else {
+        0
+    };
+
+    let announcement_only = prompt("Announcement-only room? Only moderators can post (y/n): ")
+        .eq_ignore_ascii_case("y");
+
+    // Only logged-in users can create a room they would be allowed into
+    let require_login = logged_in
+        && prompt("Only allow users logged in to an account? (y/n): ").eq_ignore_ascii_case("y");
+
+    let message = Message::CreateRoom {
+        room_name,
+        max_users,
+        max_spectators,
+        announcement_only,
+        require_login,
+        history_size,
+        history_max_age_secs,
+    };
     tx.send(serde_json::to_string(&message)?).await?;
 
     // Wait for room ID response
@@ -172,46 +1046,479 @@ async fn create_room(
     }
 }
 
+/// Senders the incoming message handler uses to report results to the menu.
+struct SignalSenders {
+    /// Set by the menu while it waits to join a room, so our own join can
+    /// be told apart from other people's.
+    joining: Arc<AtomicBool>,
+    room_id_tx: mpsc::Sender<String>,
+    menu_tx: mpsc::Sender<bool>,
+    join_tx: mpsc::Sender<bool>,
+    waitlist_tx: mpsc::Sender<usize>,
+    username_tx: mpsc::Sender<bool>,
+    auth_tx: mpsc::Sender<Option<String>>,
+    history_tx: mpsc::Sender<Backlog>,
+}
+
+/// Receivers the menu uses to learn how a join request turned out.
+struct JoinSignals {
+    joining: Arc<AtomicBool>,
+    menu_rx: mpsc::Receiver<bool>,
+    join_rx: mpsc::Receiver<bool>,
+    waitlist_rx: mpsc::Receiver<usize>,
+    username_rx: mpsc::Receiver<bool>,
+    history_rx: mpsc::Receiver<Backlog>,
+}
+
+/// What the server sends along with a successful join, held until the chat
+/// screen is ready to show it.
+enum Backlog {
+    /// A message replayed from the room's recent history.
+    Message(String),
+    /// One of the room's pinned messages.
+    Pinned(String),
+    /// One of the room's open polls.
+    Poll(String),
+}
+
+/// Sends a join request and waits for the outcome, asking for another
+/// username if the server rejects ours. Returns whether we joined the room.
+async fn join_room(
+    tx: &mpsc::Sender<String>,
+    room_id: &str,
+    mut username: String,
+    wait: bool,
+    spectate: bool,
+    signals: &mut JoinSignals,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    // Discard notices left over from an earlier attempt
+    while signals.waitlist_rx.try_recv().is_ok() {}
+    while signals.username_rx.try_recv().is_ok() {}
+    while signals.history_rx.try_recv().is_ok() {}
+
+    loop {
+        signals.joining.store(true, Ordering::SeqCst);
+        join_room_by_id(tx, room_id, &username, wait, spectate).await?;
+
+        // Wait for join result
+        let result = tokio::select! {
+            Some(_) = signals.menu_rx.recv() => Some(false),
+            Some(_) = signals.join_rx.recv() => Some(true),
+            Some(_) = signals.username_rx.recv() => None,
+            Some(_) = signals.waitlist_rx.recv() => {
+                // Queued: no timeout, the server admits us when a seat frees up
+                tokio::select! {
+                    Some(_) = signals.menu_rx.recv() => Some(false),
+                    Some(_) = signals.join_rx.recv() => Some(true),
+                }
+            }
+            _ = tokio::time::sleep(tokio::time::Duration::from_secs(2)) => {
+                println!("\nConnection timeout. Returning to menu...");
+                Some(false)
+            }
+        };
+
+        signals.joining.store(false, Ordering::SeqCst);
+        match result {
+            Some(joined) => return Ok(joined),
+            None => username = prompt("Enter a different username: "),
+        }
+    }
+}
+
 async fn join_room_by_id(
     tx: &mpsc::Sender<String>,
     room_id: &str,
     username: &str,
+    wait: bool,
+    spectate: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let message = Message::JoinRoom {
         room_id: room_id.to_string(),
         username: username.to_string(),
+        wait,
+        spectate,
     };
     tx.send(serde_json::to_string(&message)?).await?;
     Ok(())
 }
 
+// ============================================================================
+// Account Operations
+// ============================================================================
+
+/// Logs in or registers, returning the account name on success. Note the
+/// password is typed in plain view and sent over the unencrypted connection.
+async fn authenticate(
+    tx: &mpsc::Sender<String>,
+    register: bool,
+    auth_rx: &mut mpsc::Receiver<Option<String>>,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let username = prompt("Username: ");
+    let password = prompt("Password: ");
+
+    if register && prompt("Confirm password: ") != password {
+        println!("Passwords don't match.");
+        return Ok(None);
+    }
+
+    // Discard results left over from an earlier attempt
+    while auth_rx.try_recv().is_ok() {}
+
+    let message = if register {
+        Message::Register { username, password }
+    } else {
+        Message::Login { username, password }
+    };
+    tx.send(serde_json::to_string(&message)?).await?;
+
+    // Registration and login hash the password, so allow a little longer
+    tokio::select! {
+        Some(result) = auth_rx.recv() => Ok(result),
+        _ = tokio::time::sleep(tokio::time::Duration::from_secs(5)) => {
+            println!("Timeout waiting for the server.");
+            Ok(None)
+        }
+    }
+}
+
 // ============================================================================
 // Chat Loop
 // ============================================================================
 
-async fn chat_loop(tx: &mpsc::Sender<String>) -> Result<(), Box<dyn std::error::Error>> {
+async fn chat_loop(
+    tx: &mpsc::Sender<String>,
+    resume_token: &ResumeToken,
+    presence: &SharedPresence,
+    message_ids: &MessageIds,
+    mentions: &Mentions,
+    history_rx: &mut mpsc::Receiver<Backlog>,
+    room_id: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
     println!("Welcome to the chat room!");
     println!("Type /help for available commands\n");
 
+    let mut replayed = false;
+    let mut pinned = Vec::new();
+    let mut polls = Vec::new();
+    while let Ok(backlog) = history_rx.try_recv() {
+        match backlog {
+            Backlog::Message(line) => {
+                if !replayed {
+                    println!("--- Recent messages ---");
+                    replayed = true;
+                }
+                println!("{}", line);
+            }
+            Backlog::Pinned(line) => pinned.push(line),
+            Backlog::Poll(poll) => polls.push(poll),
+        }
+    }
+    if replayed {
+        println!("--- End of recent messages ---\n");
+    }
+    if !pinned.is_empty() {
+        println!("--- Pinned messages ---");
+        for line in pinned {
+            println!("{}", line);
+        }
+        println!("--- End of pinned messages ---\n");
+    }
+    if !polls.is_empty() {
+        println!("--- Open polls ---");
+        for poll in polls {
+            println!("{}", poll);
+        }
+        println!("--- Vote with /vote <poll> <option> ---\n");
+    }
+
+    let idle_after = {
+        let mut state = presence.lock().unwrap();
+        state.last_input = Instant::now();
+        state.idle_after
+    };
+    let idle_watcher = idle_after
+        .map(|idle_after| tokio::spawn(auto_away(tx.clone(), Arc::clone(presence), idle_after)));
+
     loop {
-        let input = read_line();
+        let input = read_chat_line(tx);
+        note_activity(tx, presence).await?;
 
         if input.starts_with('/') {
-            match input.as_str() {
+            let (command, argument) = input.split_once(' ').unwrap_or((input.as_str(), ""));
+            match command {
                 "/help" => show_help(),
                 "/count" => {
                     let msg = Message::GetRoomInfo;
                     tx.send(serde_json::to_string(&msg)?).await?;
                 }
-                "/leave" => break,
+                "/spectators" => match argument.trim().parse::<usize>() {
+                    Ok(max_spectators) => {
+                        let msg = Message::SetMaxSpectators { max_spectators };
+                        tx.send(serde_json::to_string(&msg)?).await?;
+                    }
+                    Err(_) => println!("Usage: /spectators <max>"),
+                },
+                "/announce" => match argument.trim() {
+                    "on" | "off" => {
+                        let msg = Message::SetAnnouncementOnly { enabled: argument.trim() == "on" };
+                        tx.send(serde_json::to_string(&msg)?).await?;
+                    }
+                    _ => println!("Usage: /announce on|off"),
+                },
+                "/mod" | "/unmod" | "/allow" | "/disallow" => {
+                    let username = argument.trim().to_string();
+                    if username.is_empty() {
+                        println!("Usage: {} <username>", command);
+                        continue;
+                    }
+                    let msg = match command {
+                        "/mod" => Message::SetModerator { username, enabled: true },
+                        "/unmod" => Message::SetModerator { username, enabled: false },
+                        "/allow" => Message::SetPoster { username, enabled: true },
+                        _ => Message::SetPoster { username, enabled: false },
+                    };
+                    tx.send(serde_json::to_string(&msg)?).await?;
+                }
+                "/nick" => {
+                    let username = argument.trim().to_string();
+                    if username.is_empty() {
+                        println!("Usage: /nick <name>");
+                        continue;
+                    }
+                    let msg = Message::ChangeNick { username };
+                    tx.send(serde_json::to_string(&msg)?).await?;
+                }
+                "/msg" => {
+                    let Some((to, content)) = argument.trim().split_once(' ') else {
+                        println!("Usage: /msg <user> <text>");
+                        continue;
+                    };
+                    let msg = Message::DirectMessage { to: to.to_string(), content: content.trim().to_string() };
+                    tx.send(serde_json::to_string(&msg)?).await?;
+                }
+                "/later" | "/at" => {
+                    let (time, content) = argument.trim().split_once(' ').unwrap_or((argument.trim(), ""));
+                    let content = content.trim().to_string();
+                    let send_at = if command == "/later" {
+                        parse_duration(time)
+                            .and_then(|delay| TimeDelta::from_std(delay).ok())
+                            .map(|delay| Utc::now() + delay)
+                    } else {
+                        next_local_time(time)
+                    };
+                    let Some(send_at) = send_at.filter(|_| !content.is_empty()) else {
+                        if command == "/later" {
+                            println!("Usage: /later <time> <text>, e.g. /later 30m standup in 5 minutes");
+                        } else {
+                            println!("Usage: /at <HH:MM> <text>, e.g. /at 09:00 standup time");
+                        }
+                        continue;
+                    };
+                    let send_at = send_at.to_rfc3339_opts(SecondsFormat::Millis, true);
+                    let msg = Message::ScheduleMessage { content, send_at };
+                    tx.send(serde_json::to_string(&msg)?).await?;
+                }
+                "/scheduled" => {
+                    tx.send(serde_json::to_string(&Message::GetScheduledMessages)?).await?;
+                }
+                "/cancel" => {
+                    let Ok(id) = argument.trim().trim_start_matches('#').parse::<u64>() else {
+                        println!("Usage: /cancel <id>, with the id shown by /scheduled");
+                        continue;
+                    };
+                    let msg = Message::CancelScheduledMessage { id };
+                    tx.send(serde_json::to_string(&msg)?).await?;
+                }
+                "/ephemeral" => {
+                    let (time, content) = argument.trim().split_once(' ').unwrap_or((argument.trim(), ""));
+                    let content = content.trim().to_string();
+                    let Some(ttl) = parse_duration(time).filter(|_| !content.is_empty()) else {
+                        println!("Usage: /ephemeral <time> <text>, e.g. /ephemeral 5m the password is ...");
+                        continue;
+                    };
+                    let msg = Message::Chat { content, reply_to: None, ttl_seconds: Some(ttl.as_secs()) };
+                    tx.send(serde_json::to_string(&msg)?).await?;
+                }
+                "/history" => {
+                    let before = match argument.trim() {
+                        "" => None,
+                        value => match value.trim_start_matches('#').parse::<u64>() {
+                            Ok(before) => Some(before),
+                            Err(_) => {
+                                println!("Usage: /history [before]");
+                                continue;
+                            }
+                        },
+                    };
+                    let msg = Message::GetHistory {
+                        room_id: room_id.to_string(),
+                        before,
+                        limit: HISTORY_PAGE_SIZE,
+                    };
+                    tx.send(serde_json::to_string(&msg)?).await?;
+                }
+                "/search" => {
+                    let query = argument.trim().to_string();
+                    if query.is_empty() {
+                        println!("Usage: /search <terms>");
+                        continue;
+                    }
+                    let msg = Message::SearchHistory {
+                        room_id: room_id.to_string(),
+                        query,
+                        limit: SEARCH_RESULT_LIMIT,
+                    };
+                    tx.send(serde_json::to_string(&msg)?).await?;
+                }
+                "/mentions" => {
+                    let mentions = mentions.lock().unwrap();
+                    if mentions.is_empty() {
+                        println!("Nobody has mentioned you in this room yet.");
+                        continue;
+                    }
+                    println!("\n--- Mentions ---");
+                    for message in mentions.iter() {
+                        println!("{}", format_entry(message, message_ids));
+                    }
+                    println!("--- End of mentions ---");
+                }
+                "/edit" => {
+                    let (number, content) = argument.trim().split_once(' ').unwrap_or((argument.trim(), ""));
+                    let content = content.trim().to_string();
+                    let Some(message_id) = known_message(number, message_ids).filter(|_| !content.is_empty()) else {
+                        println!("Usage: /edit <number> <text>, with the number shown next to a message");
+                        continue;
+                    };
+                    let msg = Message::EditMessage { message_id, content };
+                    tx.send(serde_json::to_string(&msg)?).await?;
+                }
+                "/reply" => {
+                    let (number, content) = argument.trim().split_once(' ').unwrap_or((argument.trim(), ""));
+                    let content = content.trim().to_string();
+                    let Some(message_id) = known_message(number, message_ids).filter(|_| !content.is_empty()) else {
+                        println!("Usage: /reply <number> <text>, with the number shown next to a message");
+                        continue;
+                    };
+                    let msg = Message::Chat { content, reply_to: Some(message_id), ttl_seconds: None };
+                    tx.send(serde_json::to_string(&msg)?).await?;
+                }
+                "/thread" => {
+                    let Some(message_id) = known_message(argument.trim(), message_ids) else {
+                        println!("Usage: /thread <number>, with the number shown next to a message");
+                        continue;
+                    };
+                    let msg = Message::GetThread { room_id: room_id.to_string(), message_id };
+                    tx.send(serde_json::to_string(&msg)?).await?;
+                }
+                "/react" | "/unreact" => {
+                    let (number, emoji) = argument.trim().split_once(' ').unwrap_or((argument.trim(), ""));
+                    let emoji = emoji.trim().to_string();
+                    let Some(message_id) = known_message(number, message_ids).filter(|_| !emoji.is_empty()) else {
+                        println!("Usage: {} <number> <emoji>, with the number shown next to a message", command);
+                        continue;
+                    };
+                    let msg = if command == "/react" {
+                        Message::React { message_id, emoji }
+                    } else {
+                        Message::Unreact { message_id, emoji }
+                    };
+                    tx.send(serde_json::to_string(&msg)?).await?;
+                }
+                "/pin" | "/unpin" => {
+                    let Some(message_id) = known_message(argument.trim(), message_ids) else {
+                        println!("Usage: {} <number>, with the number shown next to a message", command);
+                        continue;
+                    };
+                    let msg = if command == "/pin" {
+                        Message::PinMessage { message_id }
+                    } else {
+                        Message::UnpinMessage { message_id }
+                    };
+                    tx.send(serde_json::to_string(&msg)?).await?;
+                }
+                "/poll" => {
+                    // A leading unquoted time sets a deadline
+                    let argument = argument.trim();
+                    let (duration, rest) = match argument.split_once(' ') {
+                        Some((time, rest)) if !time.starts_with('"') => match parse_duration(time) {
+                            Some(duration) => (Some(duration), rest),
+                            None => (None, argument),
+                        },
+                        _ => (None, argument),
+                    };
+                    let Some(mut words) = split_quoted(rest).filter(|words| words.len() >= 3) else {
+                        println!("Usage: /poll [time] \"question\" <option> <option> ..., quoting anything with spaces");
+                        continue;
+                    };
+                    let question = words.remove(0);
+                    let msg = Message::CreatePoll {
+                        question,
+                        options: words,
+                        duration_secs: duration.map(|duration| duration.as_secs()),
+                    };
+                    tx.send(serde_json::to_string(&msg)?).await?;
+                }
+                "/vote" => {
+                    let (poll, option) = argument.trim().split_once(' ').unwrap_or((argument.trim(), ""));
+                    let poll_id = poll.trim_start_matches('#').parse::<u64>().ok();
+                    let option = option.trim().parse::<usize>().ok().filter(|&option| option > 0);
+                    let (Some(poll_id), Some(option)) = (poll_id, option) else {
+                        println!("Usage: /vote <poll> <option>, with the numbers shown in the poll");
+                        continue;
+                    };
+                    let msg = Message::Vote { poll_id, option: option - 1 };
+                    tx.send(serde_json::to_string(&msg)?).await?;
+                }
+                "/closepoll" => {
+                    let Ok(poll_id) = argument.trim().trim_start_matches('#').parse::<u64>() else {
+                        println!("Usage: /closepoll <poll>");
+                        continue;
+                    };
+                    let msg = Message::ClosePoll { poll_id };
+                    tx.send(serde_json::to_string(&msg)?).await?;
+                }
+                "/delete" => {
+                    let Some(message_id) = known_message(argument.trim(), message_ids) else {
+                        println!("Usage: /delete <number>, with the number shown next to a message");
+                        continue;
+                    };
+                    let msg = Message::DeleteMessage { message_id };
+                    tx.send(serde_json::to_string(&msg)?).await?;
+                }
+                "/status" => {
+                    let (value, text) = argument.trim().split_once(' ').unwrap_or((argument.trim(), ""));
+                    let Some(new_presence) = parse_presence(value) else {
+                        println!("Usage: /status <online|away|busy|dnd> [text]");
+                        continue;
+                    };
+                    let status = Some(text.trim().to_string()).filter(|text| !text.is_empty());
+                    {
+                        let mut state = presence.lock().unwrap();
+                        state.presence = new_presence;
+                        state.status = status.clone();
+                    }
+                    let msg = Message::SetPresence { presence: new_presence, status };
+                    tx.send(serde_json::to_string(&msg)?).await?;
+                }
+                "/leave" => {
+                    // We've given up the seat, so don't try to resume it
+                    resume_token.lock().unwrap().take();
+                    tx.send(serde_json::to_string(&Message::LeaveRoom)?).await?;
+                    break;
+                }
                 _ => println!("Unknown command. Type /help for available commands."),
             }
         } else if !input.is_empty() {
-            let message = Message::Chat { content: input };
+            let message = Message::Chat { content: input, reply_to: None, ttl_seconds: None };
             tx.send(serde_json::to_string(&message)?).await?;
         }
     }
 
+    if let Some(idle_watcher) = idle_watcher {
+        idle_watcher.abort();
+    }
+
     Ok(())
 }
 
@@ -220,24 +1527,42 @@ async fn chat_loop(tx: &mpsc::Sender<String>) -> Result<(), Box<dyn std::error::
 // ============================================================================
 
 async fn handle_incoming(
-    mut reader: BufReader<tokio::net::tcp::OwnedReadHalf>,
-    _tx: mpsc::Sender<String>,
-    room_id_tx: mpsc::Sender<String>,
-    menu_tx: mpsc::Sender<bool>,
-    join_tx: mpsc::Sender<bool>,
+    reader: BufReader<tokio::net::tcp::OwnedReadHalf>,
+    senders: Arc<SignalSenders>,
+    resume_token: ResumeToken,
+    message_ids: MessageIds,
+    mentions: Mentions,
 ) {
-    let mut line = String::new();
+    let mut lines = reader.lines();
+    let mut typing = TypingStatus::default();
+    let mut shown = Transcript::default();
 
     loop {
-        line.clear();
-        match reader.read_line(&mut line).await {
-            Ok(0) => {
+        // Wake up when the typing status needs to change even if nothing arrives
+        let expiry = typing.next_expiry();
+        let next_line = tokio::select! {
+            next_line = lines.next_line() => next_line,
+            _ = tokio::time::sleep_until(expiry.unwrap_or_else(Instant::now).into()), if expiry.is_some() => {
+                typing.show();
+                continue;
+            }
+        };
+
+        typing.hide();
+        match next_line {
+            Ok(None) => {
                 println!("\nServer disconnected");
                 break;
             }
-            Ok(_) => {
-                if let Ok(message) = serde_json::from_str::<Message>(&line) {
-                    process_server_message(message, &room_id_tx, &menu_tx, &join_tx).await;
+            Ok(Some(line)) => {
+                let Ok(mut value) = serde_json::from_str::<serde_json::Value>(&line) else {
+                    continue;
+                };
+                escape_strings(&mut value);
+
+                if let Ok(message) = serde_json::from_value::<Message>(value) {
+                    process_server_message(message, &senders, &resume_token, &message_ids, &mentions, &mut typing, &mut shown)
+                        .await;
                 }
             }
             Err(e) => {
@@ -245,66 +1570,678 @@ async fn handle_incoming(
                 break;
             }
         }
+        typing.show();
     }
 }
 
 async fn process_server_message(
     message: Message,
-    room_id_tx: &mpsc::Sender<String>,
-    menu_tx: &mpsc::Sender<bool>,
-    join_tx: &mpsc::Sender<bool>,
+    senders: &SignalSenders,
+    resume_token: &ResumeToken,
+    message_ids: &MessageIds,
+    mentions: &Mentions,
+    typing: &mut TypingStatus,
+    shown: &mut Transcript,
 ) {
+    let SignalSenders {
+        joining,
+        room_id_tx,
+        menu_tx,
+        join_tx,
+        waitlist_tx,
+        username_tx,
+        auth_tx,
+        history_tx,
+    } = senders;
+
     match message {
         Message::Connected => {
             println!("\nConnected to chat server.");
         }
-        Message::RoomCreated { room_name, room_id, max_users } => {
+        Message::LoggedIn { username } => {
+            println!("\nLogged in as {}.", username);
+            let _ = auth_tx.try_send(Some(username));
+        }
+        Message::RoomCreated { room_name, room_id, max_users, max_spectators } => {
             println!("\nRoom '{}' created successfully!", room_name);
             println!("Room ID: {}", room_id);
             println!("Maximum users: {}", max_users);
+            println!("Maximum spectators: {}", max_spectators);
             println!("\nShare this Room ID with others to join your chat.");
             println!("Keep it safe - you'll need it to rejoin later!\n");
             let _ = room_id_tx.send(room_id).await;
         }
-        Message::JoinedRoom { room_name, username } => {
+        Message::JoinedRoom { room_name, username } if joining.swap(false, Ordering::SeqCst) => {
             println!("\n{} joined the room '{}'", username, room_name);
+            // Sequence numbers are per room
+            message_ids.lock().unwrap().clear();
+            mentions.lock().unwrap().clear();
+            shown.clear();
             let _ = join_tx.send(true).await;
         }
-        Message::UserMessage { username, content } => {
-            println!("{}: {}", username, content);
+        Message::JoinedRoom { username, .. } => {
+            shown.notice(format!("{} joined the room", username));
         }
-        Message::Error { message } => {
+        Message::ResumeToken { token, .. } => {
+            *resume_token.lock().unwrap() = Some(token);
+        }
+        Message::Resumed { room_name, username } => {
+            println!("\nReconnected to room '{}' as {}.", room_name, username);
+        }
+        Message::Error { message, kind: ErrorKind::ResumeFailed } => {
+            resume_token.lock().unwrap().take();
             println!("\nError: {}", message);
-            if message.contains("Room is full") || message.contains("Invalid room ID") {
+            println!("Type /leave to return to the main menu.");
+        }
+        Message::Waitlisted { room_name, position } => {
+            println!("\nRoom '{}' is full. You are #{} in line.", room_name, position);
+            println!("You'll join automatically when a seat frees up.");
+            // Position updates arrive repeatedly; only the first one needs to reach the menu
+            let _ = waitlist_tx.try_send(position);
+        }
+        Message::UserMessage {
+            id,
+            seq,
+            timestamp,
+            username,
+            content,
+            history,
+            edited_at,
+            reply_to,
+            reactions,
+            mentioned,
+            expires_at,
+            ..
+        } => {
+            let message = HistoryEntry { id, seq, timestamp, username, content, edited_at, reply_to, reactions };
+            let shown_message = ShownMessage { message: message.clone(), expires_at };
+            let line = shown_message.format(message_ids);
+            shown.push(shown_message);
+
+            if history {
+                // The menu clears the screen after joining, so the chat loop prints these
+                let _ = history_tx.try_send(Backlog::Message(line));
+            } else if mentioned {
+                typing.remove(&message.username);
+                println!("{}", highlight(&line));
+                let mut mentions = mentions.lock().unwrap();
+                mentions.push_back(message);
+                if mentions.len() > MAX_MENTIONS {
+                    mentions.pop_front();
+                }
+            } else {
+                typing.remove(&message.username);
+                println!("{}", line);
+            }
+        }
+        Message::PrivateMessage { from, to, content } => {
+            typing.remove(&from);
+            shown.notice(format!("[private] {} -> {}: {}", from, to, content));
+        }
+        Message::History { messages, .. } => {
+            let Some(oldest) = messages.first().map(|m| m.seq) else {
+                println!("\nNo older messages.");
+                return;
+            };
+            println!("\n--- History ---");
+            for message in &messages {
+                println!("{}", format_entry(message, message_ids));
+            }
+            println!("--- Type /history {} for older messages ---", oldest);
+        }
+        Message::SearchResults { query, messages, .. } => {
+            if messages.is_empty() {
+                println!("\nNo messages match '{}'.", query);
+                return;
+            }
+            println!("\n--- {} result(s) for '{}', newest first ---", messages.len(), query);
+            for message in &messages {
+                println!("{}", format_entry(message, message_ids));
+            }
+            println!("--- End of results ---");
+        }
+        Message::Thread { messages, .. } => {
+            println!("\n--- Thread ---");
+            // Indent each reply under the message it answers
+            let mut depths: HashMap<String, usize> = HashMap::new();
+            for message in &messages {
+                let parent_depth = message.reply_to.as_ref().and_then(|quote| depths.get(&quote.id)).copied();
+                let depth = parent_depth.map_or(0, |depth| (depth + 1).min(MAX_THREAD_INDENT));
+                depths.insert(message.id.clone(), depth);
+
+                let line = if parent_depth.is_some() {
+                    format_entry(&HistoryEntry { reply_to: None, ..message.clone() }, message_ids)
+                } else {
+                    format_entry(message, message_ids)
+                };
+                for part in line.lines() {
+                    println!("{}{}", "  ".repeat(depth), part);
+                }
+            }
+            println!("--- End of thread ---");
+        }
+        Message::MessageEdited { seq, content, edited_at, by, .. } => {
+            if let Some(mention) = mentions.lock().unwrap().iter_mut().find(|m| m.seq == seq) {
+                mention.content = content.clone();
+                mention.edited_at = Some(edited_at.clone());
+            }
+            // Only the message itself keeps the new text, so a later deletion takes it away
+            if shown.edit(seq, &content, &edited_at) {
+                shown.redraw(message_ids);
+                shown.notice(format!("{} edited message #{}", by, seq));
+            } else {
+                println!("\n{} edited message #{}: {}", by, seq, content);
+            }
+        }
+        Message::MessageDeleted { seq, by, .. } => {
+            message_ids.lock().unwrap().remove(&seq);
+            mentions.lock().unwrap().retain(|m| m.seq != seq);
+            if shown.remove(seq) {
+                shown.redraw(message_ids);
+            }
+            shown.notice(format!("{} deleted message #{}", by, seq));
+        }
+        Message::PinnedMessages { messages, .. } => {
+            for message in &messages {
+                let _ = history_tx.try_send(Backlog::Pinned(format_entry(message, message_ids)));
+            }
+        }
+        Message::MessagePinned { message, by } => {
+            println!("\n{} pinned message #{}:", by, message.seq);
+            println!("{}", format_entry(&message, message_ids));
+        }
+        Message::MessageUnpinned { seq, by, .. } => {
+            println!("\n{} unpinned message #{}", by, seq);
+        }
+        Message::MessageExpired { seq, .. } => {
+            message_ids.lock().unwrap().remove(&seq);
+            mentions.lock().unwrap().retain(|m| m.seq != seq);
+            if shown.remove(seq) {
+                shown.redraw(message_ids);
+            }
+            println!("(message #{} has expired)", seq);
+        }
+        Message::OpenPolls { polls, .. } => {
+            for poll in &polls {
+                let _ = history_tx.try_send(Backlog::Poll(format_poll(poll)));
+            }
+        }
+        Message::PollStarted { poll } => {
+            println!("\n{} started a poll. Vote with /vote {} <option>", poll.created_by, poll.id);
+            println!("{}", format_poll(&poll));
+        }
+        Message::PollUpdated { poll } => {
+            let tally: Vec<String> = poll.options.iter()
+                .zip(&poll.votes)
+                .map(|(option, votes)| format!("{} {}", option, votes))
+                .collect();
+            println!("  Poll #{}: {}", poll.id, tally.join(" | "));
+        }
+        Message::PollClosed { poll, by } => {
+            match by {
+                Some(by) => println!("\n{} closed poll #{}", by, poll.id),
+                None => println!("\nPoll #{} has closed", poll.id),
+            }
+            println!("{}", format_poll(&PollSummary { closes_at: None, ..poll.clone() }));
+            println!("{}", format_poll_result(&poll));
+        }
+        Message::MessageScheduled { message } => {
+            println!(
+                "\nScheduled message #{} for {} (in {}): {}",
+                message.id,
+                format_day_time(&message.send_at),
+                time_until(&message.send_at),
+                message.content
+            );
+        }
+        Message::ScheduledMessages { messages, .. } => {
+            if messages.is_empty() {
+                println!("\nYou have no scheduled messages in this room.");
+                return;
+            }
+            println!("\n--- Scheduled messages ---");
+            for message in &messages {
+                println!("#{} {}: {}", message.id, format_day_time(&message.send_at), message.content);
+            }
+            println!("--- Cancel one with /cancel <id> ---");
+        }
+        Message::ScheduledMessageCancelled { id } => {
+            println!("\nCancelled scheduled message #{}", id);
+        }
+        Message::ReactionsUpdated { seq, reactions, .. } => {
+            if reactions.is_empty() {
+                println!("  #{} has no reactions", seq);
+            } else {
+                println!("  #{} {}", seq, format_reactions(&reactions));
+            }
+        }
+        Message::UserTyping { username, active } => {
+            typing.update(username, active);
+        }
+        Message::Error { message, kind: ErrorKind::PermissionDenied } => {
+            println!("\nPermission denied: {}", message);
+        }
+        Message::Error { message, kind: ErrorKind::UsernameTaken | ErrorKind::InvalidUsername } => {
+            println!("\nError: {}", message);
+            let _ = username_tx.try_send(true);
+        }
+        Message::Error { message, kind: ErrorKind::AuthenticationFailed | ErrorKind::RegistrationFailed } => {
+            println!("\nError: {}", message);
+            let _ = auth_tx.try_send(None);
+        }
+        Message::Error { message, kind: ErrorKind::AuthenticationRequired } => {
+            println!("\nError: {}", message);
+            println!("Returning to main menu...");
+            let _ = menu_tx.try_send(true);
+        }
+        Message::Error { message, .. } => {
+            println!("\nError: {}", message);
+            if message.contains("Room is full")
+                || message.contains("Invalid room ID")
+                || message.contains("Spectator limit reached")
+                || message.contains("not accepting spectators")
+            {
                 println!("Returning to main menu...");
                 let _ = menu_tx.send(true).await;
             }
         }
-        Message::RoomInfo { room_name, users, current_count, max_users } => {
+        Message::RoomInfo {
+            room_name,
+            users,
+            current_count,
+            max_users,
+            spectators,
+            max_spectators,
+            announcement_only,
+            moderators,
+            posters,
+            require_login,
+            pinned,
+        } => {
             println!("\n=== Room: {} ===", room_name);
+            if require_login {
+                println!("Login required: only registered accounts can join");
+            }
+            if announcement_only {
+                println!("Announcement-only: moderators and approved posters can post");
+            }
             println!("Users ({}/{}):", current_count, max_users);
             for user in users {
-                println!("  - {}", user);
+                match (user.presence, &user.status) {
+                    (Presence::Online, None) => println!("  - {}", user.username),
+                    (presence, status) => println!(
+                        "  - {} ({})",
+                        user.username,
+                        describe_presence(presence, status.as_deref())
+                    ),
+                }
+            }
+            println!("Spectators ({}/{}):", spectators.len(), max_spectators);
+            for spectator in spectators {
+                println!("  - {}", spectator);
+            }
+            if !moderators.is_empty() {
+                println!("Moderators: {}", moderators.join(", "));
+            }
+            if !posters.is_empty() {
+                println!("Approved posters: {}", posters.join(", "));
+            }
+            if !pinned.is_empty() {
+                println!("Pinned messages:");
+                for message in &pinned {
+                    println!("{}", format_entry(message, message_ids));
+                }
             }
             println!("=================\n");
         }
+        Message::PermissionsUpdated { announcement_only, moderators, posters } => {
+            if announcement_only {
+                println!("\nRoom is announcement-only. Moderators: [{}] Approved posters: [{}]",
+                    moderators.join(", "), posters.join(", "));
+            } else {
+                println!("\nEveryone can post in this room. Moderators: [{}]", moderators.join(", "));
+            }
+        }
+        Message::MaxSpectatorsSet { max_spectators } => {
+            if max_spectators == 0 {
+                println!("\nSpectators are now disabled for this room.");
+            } else {
+                println!("\nThis room now allows up to {} spectators.", max_spectators);
+            }
+        }
+        Message::PresenceChanged { username, presence, status } => {
+            println!("\n{} is now {}", username, describe_presence(presence, status.as_deref()));
+        }
+        Message::NickChanged { old, new } => {
+            shown.notice(format!("{} is now known as {}", old, new));
+        }
         Message::UserLeft { username } => {
-            println!("\n{} left the room", username);
+            shown.notice(format!("{} left the room", username));
         }
         _ => {}
     }
 }
 
+/// Looks up the ID of a message we've seen from its number as shown next to
+/// it (with or without the leading `#`).
+fn known_message(number: &str, message_ids: &MessageIds) -> Option<String> {
+    let seq = number.trim_start_matches('#').parse::<u64>().ok()?;
+    message_ids.lock().unwrap().get(&seq).cloned()
+}
+
+/// Formats a chat message as `#seq [time] user: content`, with the message
+/// it replies to quoted on the line above, and remembers its ID so commands
+/// can refer to it by number.
+fn format_entry(message: &HistoryEntry, message_ids: &MessageIds) -> String {
+    message_ids.lock().unwrap().insert(message.seq, message.id.clone());
+
+    let quote = match &message.reply_to {
+        Some(Quote { seq, username, snippet: Some(snippet), .. }) => format!("  > {} #{}: {}\n", username, seq, snippet),
+        Some(Quote { seq, .. }) => format!("  > (message #{} was deleted)\n", seq),
+        None => String::new(),
+    };
+    let edited = if message.edited_at.is_some() { " (edited)" } else { "" };
+    let reactions = if message.reactions.is_empty() {
+        String::new()
+    } else {
+        format!("\n  {}", format_reactions(&message.reactions))
+    };
+    format!(
+        "{}#{} {}{}: {}{}{}",
+        quote,
+        message.seq,
+        format_time(&message.timestamp),
+        message.username,
+        message.content,
+        edited,
+        reactions
+    )
+}
+
+/// Shortens `content` to at most `SNIPPET_LEN` characters, marking the cut,
+/// the same way the server quotes a parent message.
+fn snippet(content: &str) -> String {
+    if content.chars().count() <= SNIPPET_LEN {
+        return content.to_string();
+    }
+    let mut snippet: String = content.chars().take(SNIPPET_LEN - 1).collect();
+    snippet.push('…');
+    snippet
+}
+
+/// Formats a poll as its question followed by numbered options with their
+/// vote counts.
+fn format_poll(poll: &PollSummary) -> String {
+    let deadline = poll.closes_at.as_deref()
+        .map(|closes_at| format!(" (closes in {})", time_until(closes_at)))
+        .unwrap_or_default();
+    let mut text = format!("Poll #{}: {}{}", poll.id, poll.question, deadline);
+    for (number, (option, votes)) in poll.options.iter().zip(&poll.votes).enumerate() {
+        text.push_str(&format!("\n  {}. {} - {} vote{}", number + 1, option, votes, if *votes == 1 { "" } else { "s" }));
+    }
+    text
+}
+
+/// Describes the winning option of a closed poll, or a tie.
+fn format_poll_result(poll: &PollSummary) -> String {
+    let most = poll.votes.iter().copied().max().unwrap_or(0);
+    if most == 0 {
+        return "Result: no votes".to_string();
+    }
+    let winners: Vec<&str> = poll.options.iter()
+        .zip(&poll.votes)
+        .filter(|(_, &votes)| votes == most)
+        .map(|(option, _)| option.as_str())
+        .collect();
+    match winners.as_slice() {
+        [winner] => format!("Result: {} wins", winner),
+        winners => format!("Result: tie between {}", winners.join(", ")),
+    }
+}
+
+/// Formats reaction counts as `👍 3  🎉 1`.
+fn format_reactions(reactions: &BTreeMap<String, usize>) -> String {
+    reactions.iter()
+        .map(|(emoji, count)| format!("{} {}", emoji, count))
+        .collect::<Vec<_>>()
+        .join("  ")
+}
+
+/// Forwards outgoing messages until the connection drops, returning `true`
+/// if it stopped because the client is shutting down instead.
 async fn handle_outgoing(
-    mut writer: tokio::net::tcp::OwnedWriteHalf,
+    writer: &mut tokio::net::tcp::OwnedWriteHalf,
+    rx: &mut mpsc::Receiver<String>,
+    incoming: &mut JoinHandle<()>,
+) -> bool {
+    loop {
+        tokio::select! {
+            // The incoming handler only finishes when the server goes away
+            _ = &mut *incoming => return false,
+            message = rx.recv() => {
+                let Some(message) = message else {
+                    return true;
+                };
+                if write_line(writer, &message).await.is_err() {
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+async fn write_line(
+    writer: &mut tokio::net::tcp::OwnedWriteHalf,
+    message: &str,
+) -> io::Result<()> {
+    writer.write_all(message.as_bytes()).await?;
+    writer.write_all(b"\n").await
+}
+
+/// Runs the incoming and outgoing handlers, reconnecting whenever the
+/// connection drops. After a reconnect the latest resume token is presented
+/// first so the server gives us back our seat without a leave/join.
+async fn maintain_connection(
+    addr: &'static str,
+    mut stream: TcpStream,
     mut rx: mpsc::Receiver<String>,
+    senders: SignalSenders,
+    resume_token: ResumeToken,
+    message_ids: MessageIds,
+    mentions: Mentions,
 ) {
-    while let Some(message) = rx.recv().await {
-        if writer.write_all(message.as_bytes()).await.is_err() {
-            break;
+    let senders = Arc::new(senders);
+    let mut reconnected = false;
+
+    loop {
+        let (reader, mut writer) = stream.into_split();
+
+        let token = resume_token.lock().unwrap().clone();
+        if let (true, Some(token)) = (reconnected, token) {
+            let resume = serde_json::to_string(&Message::Resume { token }).unwrap_or_default();
+            let _ = write_line(&mut writer, &resume).await;
         }
-        if writer.write_all(b"\n").await.is_err() {
-            break;
+
+        let mut incoming = tokio::spawn(handle_incoming(
+            BufReader::new(reader),
+            Arc::clone(&senders),
+            Arc::clone(&resume_token),
+            Arc::clone(&message_ids),
+            Arc::clone(&mentions),
+        ));
+
+        if handle_outgoing(&mut writer, &mut rx, &mut incoming).await {
+            return;
+        }
+        incoming.abort();
+
+        println!("\nConnection to the server lost. Reconnecting...");
+        stream = loop {
+            tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+            if let Ok(stream) = TcpStream::connect(addr).await {
+                break stream;
+            }
+        };
+        println!("Reconnected to chat server at {}", addr);
+        reconnected = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hostile_payloads::{has_terminal_control, HOSTILE_PAYLOADS};
+
+    fn shown(seq: u64, content: &str, reply_to: Option<u64>) -> ShownMessage {
+        let message = HistoryEntry {
+            id: format!("message-{}", seq),
+            seq,
+            timestamp: String::new(),
+            username: "alice".to_string(),
+            content: content.to_string(),
+            edited_at: None,
+            reply_to: reply_to.map(|seq| Quote {
+                id: format!("message-{}", seq),
+                seq,
+                username: "alice".to_string(),
+                snippet: Some("quoted".to_string()),
+            }),
+            reactions: BTreeMap::new(),
+        };
+        ShownMessage { message, expires_at: None }
+    }
+
+    fn transcript_text(transcript: &Transcript) -> Vec<String> {
+        let message_ids = MessageIds::default();
+        transcript.lines.iter().map(|line| line.format(&message_ids)).collect()
+    }
+
+    #[test]
+    fn transcript_follows_edits_and_deletions() {
+        let mut transcript = Transcript::default();
+        transcript.push(shown(1, "secret: hunter2", None));
+        transcript.push_line(ShownLine::Notice("bob joined the room".to_string()));
+        transcript.push(shown(2, "what secret?", Some(1)));
+        transcript.push(shown(3, "tpyo", None));
+
+        assert!(transcript.edit(3, "typo", "2026-01-01T00:00:00.000Z"));
+        assert!(transcript.remove(1));
+        assert_eq!(transcript_text(&transcript), [
+            "bob joined the room",
+            "  > (message #1 was deleted)\n#2 alice: what secret?",
+            "#3 alice: typo (edited)",
+        ]);
+
+        // Changes to messages no longer on screen leave it alone
+        assert!(!transcript.edit(9, "gone", "2026-01-01T00:00:00.000Z"));
+        assert!(!transcript.remove(9));
+    }
+
+    #[test]
+    fn escape_terminal_leaves_no_terminal_controls() {
+        for payload in HOSTILE_PAYLOADS {
+            let escaped = escape_terminal(payload);
+            assert!(!has_terminal_control(&escaped), "{:?} became {:?}", payload, escaped);
+        }
+    }
+
+    #[test]
+    fn escape_terminal_shows_controls_as_visible_escapes() {
+        assert_eq!(escape_terminal("\x1b[2Jhi"), "\\u{1b}[2Jhi");
+        assert_eq!(escape_terminal("a\nb"), "a\\u{a}b");
+        assert_eq!(escape_terminal("\u{202e}txt"), "\\u{202e}txt");
+        assert_eq!(escape_terminal("plain ünïcode 🎉"), "plain ünïcode 🎉");
+    }
+
+    #[test]
+    fn escape_strings_reaches_every_field() {
+        for payload in HOSTILE_PAYLOADS {
+            let mut message = serde_json::json!({
+                "UserMessage": {
+                    "username": payload,
+                    "content": payload,
+                    "reply_to": { "username": payload, "content": payload },
+                    "reactions": { *payload: 1 },
+                    "mentions": [payload],
+                    "seq": 1,
+                }
+            });
+            escape_strings(&mut message);
+
+            let mut strings = Vec::new();
+            collect_strings(&message, &mut strings);
+            for text in strings {
+                assert!(!has_terminal_control(&text), "{:?} became {:?}", payload, text);
+            }
+        }
+    }
+
+    fn collect_strings(value: &serde_json::Value, strings: &mut Vec<String>) {
+        match value {
+            serde_json::Value::String(text) => strings.push(text.clone()),
+            serde_json::Value::Array(items) => items.iter().for_each(|item| collect_strings(item, strings)),
+            serde_json::Value::Object(fields) => {
+                for (key, field) in fields {
+                    strings.push(key.clone());
+                    collect_strings(field, strings);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn split_quoted_keeps_quoted_phrases_together() {
+        assert_eq!(
+            split_quoted(r#""Where to?" pizza "the usual place"  tacos"#).unwrap(),
+            ["Where to?", "pizza", "the usual place", "tacos"]
+        );
+        assert_eq!(split_quoted(r#"a"b c"d"#).unwrap(), ["ab cd"]);
+        assert_eq!(split_quoted(r#"yes "" no"#).unwrap(), ["yes", "", "no"]);
+        assert_eq!(split_quoted("  ").unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn split_quoted_rejects_an_open_quote() {
+        assert_eq!(split_quoted(r#""Where to? pizza"#), None);
+        assert_eq!(split_quoted(r#"pizza "tacos" ""#), None);
+    }
+
+    #[test]
+    fn parse_duration_reads_seconds_minutes_and_hours() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("5m"), Some(Duration::from_secs(5 * 60)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(2 * 60 * 60)));
+    }
+
+    #[test]
+    fn parse_duration_rejects_bad_input_and_overflow() {
+        for value in ["", "m", "5d", "5 m", "-5m", "1.5h", "5mm"] {
+            assert_eq!(parse_duration(value), None, "{:?}", value);
+        }
+        assert_eq!(parse_duration(&u64::MAX.to_string()), Some(Duration::from_secs(u64::MAX)));
+        assert_eq!(parse_duration(&format!("{}m", u64::MAX / 60 + 1)), None);
+        assert_eq!(parse_duration(&format!("{}h", u64::MAX / 3600 + 1)), None);
+        assert_eq!(parse_duration("18446744073709551616"), None);
+    }
+
+    #[test]
+    fn next_time_after_rolls_over_midnight() {
+        let at = |text: &str| DateTime::parse_from_rfc3339(text).unwrap();
+        let utc = |text: &str| at(text).with_timezone(&Utc);
+
+        let evening = at("2026-03-14T23:30:00+02:00");
+        assert_eq!(next_time_after("23:45", evening), Some(utc("2026-03-14T23:45:00+02:00")));
+        assert_eq!(next_time_after("00:15", evening), Some(utc("2026-03-15T00:15:00+02:00")));
+        // A time that has just come round means tomorrow
+        assert_eq!(next_time_after("23:30", evening), Some(utc("2026-03-15T23:30:00+02:00")));
+
+        let new_year = at("2026-12-31T23:59:00Z");
+        assert_eq!(next_time_after("00:00", new_year), Some(utc("2027-01-01T00:00:00Z")));
+
+        for value in ["24:00", "9", "9:00pm", "noon"] {
+            assert_eq!(next_time_after(value, evening), None, "{:?}", value);
         }
     }
 }
@@ -0,0 +1,801 @@
+// ============================================================================
+// Message History Storage
+// ============================================================================
+//
+// Chat messages are persisted per room through the `MessageStore` trait so
+// the backend can be swapped: an in-memory store for testing, an append-only
+// JSON-lines file, or SQLite. Retention limits are applied by `prune`, which
+// the server runs at startup and periodically afterwards.
+//
+// Search is backed by an FTS5 index in the SQLite store; the memory and file
+// stores fall back to a case-insensitive scan of their in-memory index.
+//
+// Messages are keyed by room ID. Rooms don't outlive the server process, so
+// what the file and SQLite stores keep from earlier runs can't be read back;
+// it only waits there to be pruned.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::time::Duration;
+
+use chrono::{SecondsFormat, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+type StoreResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// A chat message as persisted and returned by `GetHistory`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredMessage {
+    pub id: String,
+    pub seq: u64,
+    /// RFC 3339 UTC timestamp with millisecond precision, so timestamps
+    /// sort correctly as strings.
+    pub timestamp: String,
+    pub username: String,
+    /// Who wrote the message, for deciding who may change it. Kept by the
+    /// stores but never sent to clients.
+    #[serde(skip)]
+    pub author_id: Option<String>,
+    pub content: String,
+    /// When the content was last edited, in the same format as `timestamp`.
+    #[serde(default)]
+    pub edited_at: Option<String>,
+    /// The message this one replies to.
+    #[serde(default)]
+    pub reply_to: Option<Quote>,
+    /// Reaction counts by emoji. Reactions live with the room, so stores
+    /// leave this empty and the server fills it in when sending messages out.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub reactions: BTreeMap<String, usize>,
+}
+
+/// Longest snippet of a parent message kept with its replies, in characters.
+const SNIPPET_LEN: usize = 80;
+
+/// What a reply shows of the message it answers. The snippet follows edits
+/// to the parent and is cleared if the parent is deleted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quote {
+    pub id: String,
+    pub seq: u64,
+    pub username: String,
+    /// The start of the parent's content, or `None` once it's deleted.
+    pub snippet: Option<String>,
+}
+
+impl Quote {
+    pub fn of(message: &StoredMessage) -> Self {
+        Self {
+            id: message.id.clone(),
+            seq: message.seq,
+            username: message.username.clone(),
+            snippet: Some(snippet(&message.content)),
+        }
+    }
+}
+
+/// Shortens `content` to at most `SNIPPET_LEN` characters, marking the cut.
+pub fn snippet(content: &str) -> String {
+    if content.chars().count() <= SNIPPET_LEN {
+        return content.to_string();
+    }
+    let mut snippet: String = content.chars().take(SNIPPET_LEN - 1).collect();
+    snippet.push('…');
+    snippet
+}
+
+/// How much history to keep. `None` means no limit.
+pub struct Retention {
+    pub max_age: Option<Duration>,
+    pub max_per_room: Option<usize>,
+}
+
+impl Retention {
+    /// Oldest timestamp that is still kept, in the same format as
+    /// `StoredMessage::timestamp`.
+    fn cutoff(&self) -> Option<String> {
+        let max_age = chrono::Duration::from_std(self.max_age?).ok()?;
+        Some((Utc::now() - max_age).to_rfc3339_opts(SecondsFormat::Millis, true))
+    }
+}
+
+pub trait MessageStore: Send {
+    fn append(&mut self, room_id: &str, message: &StoredMessage) -> StoreResult<()>;
+
+    /// Up to `limit` messages from `room_id` with a sequence number below
+    /// `before` (or the newest ones if `before` is `None`), oldest first.
+    fn before(&self, room_id: &str, before: Option<u64>, limit: usize) -> StoreResult<Vec<StoredMessage>>;
+
+    /// Deletes messages outside the retention limits, returning how many
+    /// were removed.
+    fn prune(&mut self, retention: &Retention) -> StoreResult<usize>;
+
+    /// The message with the given ID, if `room_id` has it.
+    fn get(&self, room_id: &str, id: &str) -> StoreResult<Option<StoredMessage>>;
+
+    /// Replaces the content and edit time of the stored message with the
+    /// same ID, and refreshes the snippet quoted by its replies.
+    fn update(&mut self, room_id: &str, message: &StoredMessage) -> StoreResult<()>;
+
+    /// Removes a message so it no longer appears in history or search, and
+    /// clears the snippet quoted by its replies.
+    fn delete(&mut self, room_id: &str, id: &str) -> StoreResult<()>;
+
+    /// Up to `limit` messages from `room_id` containing every word of
+    /// `query`, newest first.
+    fn search(&self, room_id: &str, query: &str, limit: usize) -> StoreResult<Vec<StoredMessage>>;
+
+    /// Up to `limit` messages of the thread started by `root_id`: the root
+    /// itself (unless deleted) and every reply to it, directly or through
+    /// other replies, oldest first.
+    fn thread(&self, room_id: &str, root_id: &str, limit: usize) -> StoreResult<Vec<StoredMessage>>;
+
+    /// The ID of the message that started the thread `id` belongs to,
+    /// following replies back as far as they go. `None` if there's no
+    /// message `id` in the room.
+    fn thread_root(&self, room_id: &str, id: &str) -> StoreResult<Option<String>> {
+        let Some(mut message) = self.get(room_id, id)? else {
+            return Ok(None);
+        };
+
+        while let Some(parent) = message.reply_to.take() {
+            match self.get(room_id, &parent.id)? {
+                Some(found) => message = found,
+                // Replies to a deleted message still form a thread
+                None => return Ok(Some(parent.id)),
+            }
+        }
+        Ok(Some(message.id))
+    }
+}
+
+/// Picks a backend by name: `memory`, `file` or `sqlite`.
+pub fn open(backend: &str, path: &str) -> StoreResult<Box<dyn MessageStore>> {
+    match backend {
+        "memory" => Ok(Box::new(MemoryStore::default())),
+        "file" => Ok(Box::new(FileStore::open(path)?)),
+        "sqlite" => Ok(Box::new(SqliteStore::open(path)?)),
+        other => Err(format!("Unknown history backend '{}' (expected memory, file or sqlite)", other).into()),
+    }
+}
+
+// ============================================================================
+// In-Memory Store
+// ============================================================================
+
+#[derive(Default)]
+pub struct MemoryStore {
+    rooms: HashMap<String, Vec<StoredMessage>>,
+}
+
+impl MessageStore for MemoryStore {
+    fn append(&mut self, room_id: &str, message: &StoredMessage) -> StoreResult<()> {
+        let messages = self.rooms.entry(room_id.to_string()).or_default();
+        // Concurrent senders can finish out of order, so keep the list sorted
+        let index = messages.partition_point(|m| m.seq < message.seq);
+        messages.insert(index, message.clone());
+        Ok(())
+    }
+
+    fn before(&self, room_id: &str, before: Option<u64>, limit: usize) -> StoreResult<Vec<StoredMessage>> {
+        let Some(messages) = self.rooms.get(room_id) else {
+            return Ok(Vec::new());
+        };
+
+        let end = before.map_or(messages.len(), |before| messages.partition_point(|m| m.seq < before));
+        let start = end.saturating_sub(limit);
+        Ok(messages[start..end].to_vec())
+    }
+
+    fn prune(&mut self, retention: &Retention) -> StoreResult<usize> {
+        let cutoff = retention.cutoff();
+        let mut removed = 0;
+
+        for messages in self.rooms.values_mut() {
+            let before = messages.len();
+            if let Some(cutoff) = &cutoff {
+                messages.retain(|m| m.timestamp >= *cutoff);
+            }
+            if let Some(max) = retention.max_per_room {
+                let excess = messages.len().saturating_sub(max);
+                messages.drain(..excess);
+            }
+            removed += before - messages.len();
+        }
+
+        self.rooms.retain(|_, messages| !messages.is_empty());
+        Ok(removed)
+    }
+
+    fn search(&self, room_id: &str, query: &str, limit: usize) -> StoreResult<Vec<StoredMessage>> {
+        let Some(messages) = self.rooms.get(room_id) else {
+            return Ok(Vec::new());
+        };
+
+        let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+        Ok(messages
+            .iter()
+            .rev()
+            .filter(|m| {
+                let content = m.content.to_lowercase();
+                terms.iter().all(|term| content.contains(term.as_str()))
+            })
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    fn get(&self, room_id: &str, id: &str) -> StoreResult<Option<StoredMessage>> {
+        Ok(self.rooms.get(room_id).and_then(|messages| messages.iter().find(|m| m.id == id)).cloned())
+    }
+
+    fn update(&mut self, room_id: &str, message: &StoredMessage) -> StoreResult<()> {
+        let Some(messages) = self.rooms.get_mut(room_id) else {
+            return Ok(());
+        };
+
+        for stored in messages.iter_mut() {
+            if stored.id == message.id {
+                stored.content = message.content.clone();
+                stored.edited_at = message.edited_at.clone();
+            } else if let Some(quote) = stored.reply_to.as_mut().filter(|quote| quote.id == message.id) {
+                quote.snippet = Some(snippet(&message.content));
+            }
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, room_id: &str, id: &str) -> StoreResult<()> {
+        let Some(messages) = self.rooms.get_mut(room_id) else {
+            return Ok(());
+        };
+
+        messages.retain(|m| m.id != id);
+        for quote in messages.iter_mut().filter_map(|m| m.reply_to.as_mut()) {
+            if quote.id == id {
+                quote.snippet = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn thread(&self, room_id: &str, root_id: &str, limit: usize) -> StoreResult<Vec<StoredMessage>> {
+        let Some(messages) = self.rooms.get(room_id) else {
+            return Ok(Vec::new());
+        };
+
+        // Replies always come after what they reply to, so one pass finds them all
+        let mut ids = HashSet::from([root_id]);
+        let mut thread = Vec::new();
+        for message in messages {
+            if thread.len() == limit {
+                break;
+            }
+            if message.id == root_id || message.reply_to.as_ref().is_some_and(|quote| ids.contains(quote.id.as_str())) {
+                ids.insert(&message.id);
+                thread.push(message.clone());
+            }
+        }
+        Ok(thread)
+    }
+}
+
+// ============================================================================
+// Append-Only File Store
+// ============================================================================
+
+/// One line of the history file.
+#[derive(Serialize, Deserialize)]
+struct FileRecord {
+    room_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    author_id: Option<String>,
+    #[serde(flatten)]
+    message: StoredMessage,
+}
+
+impl FileRecord {
+    fn new(room_id: &str, message: &StoredMessage) -> Self {
+        Self { room_id: room_id.to_string(), author_id: message.author_id.clone(), message: message.clone() }
+    }
+
+    fn into_message(self) -> StoredMessage {
+        StoredMessage { author_id: self.author_id, ..self.message }
+    }
+}
+
+/// Appends each message as a JSON line and answers queries from an index
+/// loaded at startup. Pruning, edits and deletions rewrite the file, so
+/// nothing removed lingers on disk.
+pub struct FileStore {
+    path: String,
+    file: File,
+    index: MemoryStore,
+}
+
+impl FileStore {
+    pub fn open(path: &str) -> StoreResult<Self> {
+        let mut index = MemoryStore::default();
+
+        if let Ok(existing) = File::open(path) {
+            for line in BufReader::new(existing).lines() {
+                // A crash mid-write can leave a partial last line; skip it
+                if let Ok(record) = serde_json::from_str::<FileRecord>(&line?) {
+                    let room_id = record.room_id.clone();
+                    index.append(&room_id, &record.into_message())?;
+                }
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { path: path.to_string(), file, index })
+    }
+
+    fn rewrite(&mut self) -> StoreResult<()> {
+        let temp_path = format!("{}.tmp", self.path);
+        let mut temp = File::create(&temp_path)?;
+        for (room_id, messages) in &self.index.rooms {
+            for message in messages {
+                let record = FileRecord::new(room_id, message);
+                writeln!(temp, "{}", serde_json::to_string(&record)?)?;
+            }
+        }
+        temp.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+impl MessageStore for FileStore {
+    fn append(&mut self, room_id: &str, message: &StoredMessage) -> StoreResult<()> {
+        let record = FileRecord::new(room_id, message);
+        writeln!(self.file, "{}", serde_json::to_string(&record)?)?;
+        self.index.append(room_id, message)
+    }
+
+    fn before(&self, room_id: &str, before: Option<u64>, limit: usize) -> StoreResult<Vec<StoredMessage>> {
+        self.index.before(room_id, before, limit)
+    }
+
+    fn prune(&mut self, retention: &Retention) -> StoreResult<usize> {
+        let removed = self.index.prune(retention)?;
+        if removed > 0 {
+            self.rewrite()?;
+        }
+        Ok(removed)
+    }
+
+    fn search(&self, room_id: &str, query: &str, limit: usize) -> StoreResult<Vec<StoredMessage>> {
+        self.index.search(room_id, query, limit)
+    }
+
+    fn get(&self, room_id: &str, id: &str) -> StoreResult<Option<StoredMessage>> {
+        self.index.get(room_id, id)
+    }
+
+    fn update(&mut self, room_id: &str, message: &StoredMessage) -> StoreResult<()> {
+        self.index.update(room_id, message)?;
+        self.rewrite()
+    }
+
+    fn delete(&mut self, room_id: &str, id: &str) -> StoreResult<()> {
+        self.index.delete(room_id, id)?;
+        self.rewrite()
+    }
+
+    fn thread(&self, room_id: &str, root_id: &str, limit: usize) -> StoreResult<Vec<StoredMessage>> {
+        self.index.thread(room_id, root_id, limit)
+    }
+}
+
+// ============================================================================
+// SQLite Store
+// ============================================================================
+
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+/// Columns read back into a `StoredMessage`, in the order `message_from_row`
+/// expects.
+const MESSAGE_COLUMNS: &str = "id, seq, timestamp, username, content, edited_at,
+    reply_id, reply_seq, reply_username, reply_snippet, author_id";
+
+/// Columns added since the table was first created, with their types.
+const ADDED_COLUMNS: [(&str, &str); 6] = [
+    ("edited_at", "TEXT"),
+    ("reply_id", "TEXT"),
+    ("reply_seq", "INTEGER"),
+    ("reply_username", "TEXT"),
+    ("reply_snippet", "TEXT"),
+    ("author_id", "TEXT"),
+];
+
+impl SqliteStore {
+    pub fn open(path: &str) -> StoreResult<Self> {
+        let conn = Connection::open(path)?;
+        // Overwrite deleted content instead of leaving it in free pages
+        conn.pragma_update(None, "secure_delete", true)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                room_id        TEXT NOT NULL,
+                seq            INTEGER NOT NULL,
+                id             TEXT NOT NULL UNIQUE,
+                timestamp      TEXT NOT NULL,
+                username       TEXT NOT NULL,
+                content        TEXT NOT NULL,
+                edited_at      TEXT,
+                reply_id       TEXT,
+                reply_seq      INTEGER,
+                reply_username TEXT,
+                reply_snippet  TEXT,
+                author_id      TEXT,
+                PRIMARY KEY (room_id, seq)
+            );
+            CREATE INDEX IF NOT EXISTS messages_timestamp ON messages (timestamp);",
+        )?;
+
+        // Bring databases from older versions up to date
+        for (column, column_type) in ADDED_COLUMNS {
+            let exists: bool = conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM pragma_table_info('messages') WHERE name = ?1)",
+                [column],
+                |row| row.get(0),
+            )?;
+            if !exists {
+                conn.execute(&format!("ALTER TABLE messages ADD COLUMN {} {}", column, column_type), [])?;
+            }
+        }
+        conn.execute("CREATE INDEX IF NOT EXISTS messages_reply ON messages (room_id, reply_id)", [])?;
+
+        // The FTS index mirrors `messages` by rowid and is kept in sync by
+        // triggers. If it's new, fill it from any messages already stored.
+        let has_index: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE name = 'messages_fts')",
+            [],
+            |row| row.get(0),
+        )?;
+        conn.execute_batch(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts
+                USING fts5(content, content = 'messages', content_rowid = 'rowid');
+            CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+                INSERT INTO messages_fts (rowid, content) VALUES (new.rowid, new.content);
+            END;
+            CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+                INSERT INTO messages_fts (messages_fts, rowid, content) VALUES ('delete', old.rowid, old.content);
+            END;
+            CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF content ON messages BEGIN
+                INSERT INTO messages_fts (messages_fts, rowid, content) VALUES ('delete', old.rowid, old.content);
+                INSERT INTO messages_fts (rowid, content) VALUES (new.rowid, new.content);
+            END;",
+        )?;
+        if !has_index {
+            conn.execute("INSERT INTO messages_fts (messages_fts) VALUES ('rebuild')", [])?;
+        }
+        // Likewise scrub deleted terms from the index right away
+        conn.execute("INSERT INTO messages_fts (messages_fts, rank) VALUES ('secure-delete', 1)", [])?;
+
+        Ok(Self { conn })
+    }
+
+    fn query(&self, sql: &str, params: impl rusqlite::Params) -> StoreResult<Vec<StoredMessage>> {
+        let mut statement = self.conn.prepare(sql)?;
+        let rows = statement.query_map(params, message_from_row)?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+}
+
+/// Turns free text into an FTS5 query that matches every word literally,
+/// so punctuation in the search terms can't be read as query syntax.
+fn fts_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn message_from_row(row: &rusqlite::Row) -> rusqlite::Result<StoredMessage> {
+    let reply_id: Option<String> = row.get(6)?;
+    let reply_to = match reply_id {
+        Some(id) => Some(Quote {
+            id,
+            seq: row.get(7)?,
+            username: row.get(8)?,
+            snippet: row.get(9)?,
+        }),
+        None => None,
+    };
+
+    Ok(StoredMessage {
+        id: row.get(0)?,
+        seq: row.get(1)?,
+        timestamp: row.get(2)?,
+        username: row.get(3)?,
+        author_id: row.get(10)?,
+        content: row.get(4)?,
+        edited_at: row.get(5)?,
+        reply_to,
+        reactions: BTreeMap::new(),
+    })
+}
+
+impl MessageStore for SqliteStore {
+    fn append(&mut self, room_id: &str, message: &StoredMessage) -> StoreResult<()> {
+        let reply_to = message.reply_to.as_ref();
+        self.conn.execute(
+            "INSERT INTO messages (room_id, seq, id, timestamp, username, content, edited_at,
+                                   reply_id, reply_seq, reply_username, reply_snippet, author_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            params![
+                room_id,
+                message.seq,
+                message.id,
+                message.timestamp,
+                message.username,
+                message.content,
+                message.edited_at,
+                reply_to.map(|quote| &quote.id),
+                reply_to.map(|quote| quote.seq),
+                reply_to.map(|quote| &quote.username),
+                reply_to.and_then(|quote| quote.snippet.as_ref()),
+                message.author_id,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn before(&self, room_id: &str, before: Option<u64>, limit: usize) -> StoreResult<Vec<StoredMessage>> {
+        // SQLite integers are signed, and no sequence number gets near the top
+        let before = before.map_or(i64::MAX, |before| i64::try_from(before).unwrap_or(i64::MAX));
+        let mut messages = self.query(
+            &format!(
+                "SELECT {} FROM messages
+                 WHERE room_id = ?1 AND seq < ?2
+                 ORDER BY seq DESC LIMIT ?3",
+                MESSAGE_COLUMNS
+            ),
+            params![room_id, before, limit],
+        )?;
+        messages.reverse();
+        Ok(messages)
+    }
+
+    fn prune(&mut self, retention: &Retention) -> StoreResult<usize> {
+        let mut removed = 0;
+
+        if let Some(cutoff) = retention.cutoff() {
+            removed += self.conn.execute("DELETE FROM messages WHERE timestamp < ?1", params![cutoff])?;
+        }
+
+        if let Some(max) = retention.max_per_room {
+            removed += self.conn.execute(
+                "DELETE FROM messages WHERE (room_id, seq) IN (
+                    SELECT room_id, seq FROM (
+                        SELECT room_id, seq,
+                               ROW_NUMBER() OVER (PARTITION BY room_id ORDER BY seq DESC) AS newer
+                        FROM messages
+                    ) WHERE newer > ?1
+                )",
+                params![max],
+            )?;
+        }
+
+        Ok(removed)
+    }
+
+    fn get(&self, room_id: &str, id: &str) -> StoreResult<Option<StoredMessage>> {
+        let message = self.conn.query_row(
+            &format!("SELECT {} FROM messages WHERE room_id = ?1 AND id = ?2", MESSAGE_COLUMNS),
+            params![room_id, id],
+            message_from_row,
+        ).optional()?;
+        Ok(message)
+    }
+
+    fn update(&mut self, room_id: &str, message: &StoredMessage) -> StoreResult<()> {
+        self.conn.execute(
+            "UPDATE messages SET content = ?3, edited_at = ?4 WHERE room_id = ?1 AND id = ?2",
+            params![room_id, message.id, message.content, message.edited_at],
+        )?;
+        self.conn.execute(
+            "UPDATE messages SET reply_snippet = ?3 WHERE room_id = ?1 AND reply_id = ?2",
+            params![room_id, message.id, snippet(&message.content)],
+        )?;
+        Ok(())
+    }
+
+    fn delete(&mut self, room_id: &str, id: &str) -> StoreResult<()> {
+        self.conn.execute("DELETE FROM messages WHERE room_id = ?1 AND id = ?2", params![room_id, id])?;
+        self.conn.execute(
+            "UPDATE messages SET reply_snippet = NULL WHERE room_id = ?1 AND reply_id = ?2",
+            params![room_id, id],
+        )?;
+        Ok(())
+    }
+
+    fn search(&self, room_id: &str, query: &str, limit: usize) -> StoreResult<Vec<StoredMessage>> {
+        self.query(
+            &format!(
+                "SELECT {} FROM messages
+                 WHERE rowid IN (SELECT rowid FROM messages_fts WHERE messages_fts MATCH ?1)
+                   AND room_id = ?2
+                 ORDER BY seq DESC LIMIT ?3",
+                MESSAGE_COLUMNS
+            ),
+            params![fts_query(query), room_id, limit],
+        )
+    }
+
+    fn thread(&self, room_id: &str, root_id: &str, limit: usize) -> StoreResult<Vec<StoredMessage>> {
+        self.query(
+            &format!(
+                "WITH RECURSIVE thread (id) AS (
+                    SELECT ?2
+                    UNION
+                    SELECT messages.id FROM messages JOIN thread ON messages.reply_id = thread.id
+                    WHERE messages.room_id = ?1
+                )
+                SELECT {} FROM messages
+                WHERE room_id = ?1 AND id IN thread
+                ORDER BY seq LIMIT ?3",
+                MESSAGE_COLUMNS
+            ),
+            params![room_id, root_id, limit],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(seq: u64, content: &str) -> StoredMessage {
+        StoredMessage {
+            id: format!("message-{}", seq),
+            seq,
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            username: "alice".to_string(),
+            author_id: Some("session:alice".to_string()),
+            content: content.to_string(),
+            edited_at: None,
+            reply_to: None,
+            reactions: BTreeMap::new(),
+        }
+    }
+
+    fn reply(seq: u64, content: &str, parent: &StoredMessage) -> StoredMessage {
+        StoredMessage { reply_to: Some(Quote::of(parent)), ..message(seq, content) }
+    }
+
+    fn seqs(messages: StoreResult<Vec<StoredMessage>>) -> Vec<u64> {
+        messages.unwrap().iter().map(|m| m.seq).collect()
+    }
+
+    /// What every backend must do the same way.
+    fn check_contract(store: &mut dyn MessageStore) {
+        let contents = ["alpha one", "beta two", "gamma three", "alpha four", "delta five"];
+        let messages: Vec<StoredMessage> =
+            contents.iter().enumerate().map(|(i, content)| message(i as u64 + 1, content)).collect();
+        for message in &messages {
+            store.append("a", message).unwrap();
+        }
+        store.append("b", &StoredMessage { id: "other".to_string(), ..message(1, "alpha elsewhere") }).unwrap();
+
+        // Paging, oldest first
+        assert_eq!(seqs(store.before("a", None, 3)), [3, 4, 5]);
+        assert_eq!(seqs(store.before("a", Some(3), 10)), [1, 2]);
+        assert_eq!(seqs(store.before("a", Some(u64::MAX), 2)), [4, 5]);
+        assert_eq!(seqs(store.before("missing", None, 10)), Vec::<u64>::new());
+
+        // Rooms are kept apart
+        assert_eq!(store.get("a", "message-2").unwrap().unwrap().content, "beta two");
+        assert!(store.get("b", "message-2").unwrap().is_none());
+        assert_eq!(store.get("a", "message-2").unwrap().unwrap().author_id.as_deref(), Some("session:alice"));
+
+        // Search needs every word, ignores case and returns the newest first
+        assert_eq!(seqs(store.search("a", "alpha", 10)), [4, 1]);
+        assert_eq!(seqs(store.search("a", "ALPHA four", 10)), [4]);
+        assert_eq!(seqs(store.search("a", "alpha", 1)), [4]);
+        assert_eq!(seqs(store.search("b", "alpha", 10)), [1]);
+
+        // Threads follow replies of replies
+        let first_reply = reply(6, "reply to beta", &messages[1]);
+        store.append("a", &first_reply).unwrap();
+        store.append("a", &reply(7, "reply to the reply", &first_reply)).unwrap();
+        assert_eq!(seqs(store.thread("a", "message-2", 10)), [2, 6, 7]);
+        assert_eq!(store.thread_root("a", "message-7").unwrap().as_deref(), Some("message-2"));
+
+        // Edits reach search and the quotes in replies
+        let edited = StoredMessage {
+            content: "beta edited".to_string(),
+            edited_at: Some(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)),
+            ..messages[1].clone()
+        };
+        store.update("a", &edited).unwrap();
+        assert_eq!(store.get("a", "message-2").unwrap().unwrap().content, "beta edited");
+        assert!(store.get("a", "message-2").unwrap().unwrap().edited_at.is_some());
+        let quote = store.get("a", "message-6").unwrap().unwrap().reply_to.unwrap();
+        assert_eq!(quote.snippet.as_deref(), Some("beta edited"));
+        assert_eq!(seqs(store.search("a", "edited", 10)), [2]);
+        assert_eq!(seqs(store.search("a", "two", 10)), Vec::<u64>::new());
+
+        // Deleting leaves replies in place with an empty quote
+        store.delete("a", "message-2").unwrap();
+        assert!(store.get("a", "message-2").unwrap().is_none());
+        assert!(store.get("a", "message-6").unwrap().unwrap().reply_to.unwrap().snippet.is_none());
+        assert_eq!(store.thread_root("a", "message-7").unwrap().as_deref(), Some("message-2"));
+        assert_eq!(seqs(store.thread("a", "message-2", 10)), [6, 7]);
+        assert_eq!(seqs(store.before("a", None, 10)), [1, 3, 4, 5, 6, 7]);
+
+        // Retention by age, then by count per room
+        let old = StoredMessage {
+            id: "old".to_string(),
+            timestamp: "2000-01-01T00:00:00.000Z".to_string(),
+            ..message(1, "old")
+        };
+        store.append("c", &old).unwrap();
+        let by_age = Retention { max_age: Some(Duration::from_secs(24 * 60 * 60)), max_per_room: None };
+        assert_eq!(store.prune(&by_age).unwrap(), 1);
+        assert!(store.get("c", "old").unwrap().is_none());
+
+        let by_count = Retention { max_age: None, max_per_room: Some(2) };
+        assert_eq!(store.prune(&by_count).unwrap(), 4);
+        assert_eq!(seqs(store.before("a", None, 10)), [6, 7]);
+        assert_eq!(seqs(store.before("b", None, 10)), [1]);
+    }
+
+    /// A file path for one test, removed when dropped.
+    struct TempPath(String);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("rust-chat-{}-{}", std::process::id(), name));
+            let _ = fs::remove_file(&path);
+            Self(path.to_string_lossy().into_owned())
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+            let _ = fs::remove_file(format!("{}.tmp", self.0));
+        }
+    }
+
+    #[test]
+    fn memory_store_meets_contract() {
+        check_contract(&mut MemoryStore::default());
+    }
+
+    #[test]
+    fn file_store_meets_contract() {
+        let path = TempPath::new("contract.jsonl");
+        check_contract(&mut FileStore::open(&path.0).unwrap());
+    }
+
+    #[test]
+    fn sqlite_store_meets_contract() {
+        check_contract(&mut SqliteStore::open(":memory:").unwrap());
+    }
+
+    #[test]
+    fn file_store_reloads_what_it_wrote() {
+        let path = TempPath::new("reload.jsonl");
+        {
+            let mut store = FileStore::open(&path.0).unwrap();
+            store.append("a", &message(1, "kept")).unwrap();
+            store.append("a", &message(2, "deleted")).unwrap();
+            store.delete("a", "message-2").unwrap();
+            store.append("a", &message(3, "appended after a rewrite")).unwrap();
+        }
+
+        let store = FileStore::open(&path.0).unwrap();
+        assert_eq!(seqs(store.before("a", None, 10)), [1, 3]);
+        assert_eq!(store.get("a", "message-1").unwrap().unwrap().author_id.as_deref(), Some("session:alice"));
+    }
+}
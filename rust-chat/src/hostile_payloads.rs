@@ -0,0 +1,44 @@
+// ============================================================================
+// Hostile Payloads
+// ============================================================================
+//
+// Chat text that would hijack a terminal if it were printed verbatim. Shared
+// by the server's sanitizing tests and the client's escaping tests, and sent
+// over the wire by demo-hostile-payloads.sh.
+
+pub const HOSTILE_PAYLOADS: &[&str] = &[
+    "\x1b[2J\x1b[Hclear screen and move cursor home",
+    "\x1b[1A\x1b[2Kbob: rewrite the previous line",
+    "\x1b[31mred text that never resets",
+    "\x1b[6nrequest a cursor position report",
+    "\x1b]0;pwned\x07set the window title",
+    "\x1b]8;;https://evil.example\x1b\\innocent link\x1b]8;;\x1b\\",
+    "\x1b]52;c;ZWNobyBwd25lZA==\x07write to the clipboard",
+    "\x1bPq#0;2;0;0;0#0~~@@vv@@~~$\x1b\\DCS sixel sequence",
+    "\u{9b}2J8-bit CSI clear screen",
+    "\u{9d}0;pwned\u{9c}8-bit OSC window title",
+    "hello\nbob: fake line from bob",
+    "hello\rbob: overwrite my own line",
+    "hello\u{2028}bob: Unicode line separator",
+    "\u{202e}exe.txt\u{202c} right-to-left override",
+    "\u{2066}isolate\u{2069} bidi isolate",
+    "\u{61c}arabic letter mark\u{200f}",
+    "\x07\x07ring the bell",
+    "\0null byte",
+    "\x08\x08\x08backspace over text",
+    "\x1b\x07\u{9b}",
+];
+
+/// Whether `text` still holds anything a terminal would act on: ESC, BEL or
+/// any other C0 or C1 control, a line or paragraph separator, or a
+/// bidirectional formatting character.
+pub fn has_terminal_control(text: &str) -> bool {
+    text.chars().any(|c| {
+        c.is_control()
+            || matches!(
+                c,
+                '\u{2028}' | '\u{2029}' | '\u{061C}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}'
+                    | '\u{2066}'..='\u{2069}'
+            )
+    })
+}
@@ -1,52 +1,652 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, SecondsFormat, Utc};
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{Mutex, RwLock};
 use uuid::Uuid;
 
+mod accounts;
+mod history;
+#[cfg(test)]
+mod hostile_payloads;
+mod tokens;
+mod validation;
+
+use accounts::{AccountStore, MAX_PASSWORD_LEN, MIN_PASSWORD_LEN};
+use history::{MessageStore, Quote, Retention, StoredMessage};
+use validation::{
+    sanitize_chat, sanitize_poll, sanitize_search, sanitize_status, skeleton, validate_reaction, ROOM_NAME_RULES,
+    USERNAME_RULES,
+};
+
 // ============================================================================
 // Message Types
 // ============================================================================
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(clippy::enum_variant_names)]
 enum Message {
     // Client -> Server
-    CreateRoom { room_name: String, max_users: usize },
-    JoinRoom { room_id: String, username: String },
-    Chat { content: String },
+    CreateRoom {
+        room_name: String,
+        max_users: usize,
+        #[serde(default = "default_max_spectators")]
+        max_spectators: usize,
+        #[serde(default)]
+        announcement_only: bool,
+        #[serde(default)]
+        require_login: bool,
+        #[serde(default = "default_history_size")]
+        history_size: usize,
+        #[serde(default = "default_history_max_age_secs")]
+        history_max_age_secs: u64,
+    },
+    JoinRoom {
+        room_id: String,
+        username: String,
+        #[serde(default)]
+        wait: bool,
+        #[serde(default)]
+        spectate: bool,
+    },
+    /// Give up your seat, spectator place or place in line.
+    LeaveRoom,
+    Chat {
+        content: String,
+        /// ID of an earlier message in the room this one answers.
+        #[serde(default)]
+        reply_to: Option<String>,
+        /// Makes the message ephemeral: it's never stored and disappears
+        /// from the room after this many seconds.
+        #[serde(default)]
+        ttl_seconds: Option<u64>,
+    },
     GetRoomInfo,
+    SetMaxSpectators { max_spectators: usize },
+    SetAnnouncementOnly { enabled: bool },
+    SetModerator { username: String, enabled: bool },
+    SetPoster { username: String, enabled: bool },
+    Register { username: String, password: String },
+    Login { username: String, password: String },
+    Resume { token: String },
+    ChangeNick { username: String },
+    SetPresence {
+        presence: Presence,
+        #[serde(default)]
+        status: Option<String>,
+    },
+    Typing { active: bool },
+    DirectMessage { to: String, content: String },
+    GetHistory {
+        room_id: String,
+        /// Only return messages with a lower sequence number than this.
+        #[serde(default)]
+        before: Option<u64>,
+        #[serde(default = "default_history_page")]
+        limit: usize,
+    },
+    SearchHistory {
+        room_id: String,
+        query: String,
+        #[serde(default = "default_search_limit")]
+        limit: usize,
+    },
+    GetThread { room_id: String, message_id: String },
+    EditMessage { message_id: String, content: String },
+    DeleteMessage { message_id: String },
+    React { message_id: String, emoji: String },
+    Unreact { message_id: String, emoji: String },
+    PinMessage { message_id: String },
+    UnpinMessage { message_id: String },
+    CreatePoll {
+        question: String,
+        options: Vec<String>,
+        /// Closes the poll by itself after this many seconds.
+        #[serde(default)]
+        duration_secs: Option<u64>,
+    },
+    /// Votes for `options[option]`, replacing any earlier vote in the poll.
+    Vote { poll_id: u64, option: usize },
+    ClosePoll { poll_id: u64 },
+    /// Posts `content` to your room at `send_at`, an RFC 3339 time.
+    ScheduleMessage { content: String, send_at: String },
+    GetScheduledMessages,
+    CancelScheduledMessage { id: u64 },
 
     // Server -> Client
     Connected,
-    RoomCreated { room_name: String, room_id: String, max_users: usize },
+    LoggedIn { username: String },
+    RoomCreated { room_name: String, room_id: String, max_users: usize, max_spectators: usize },
     JoinedRoom { room_name: String, username: String },
-    UserMessage { username: String, content: String },
-    RoomInfo { room_name: String, users: Vec<String>, current_count: usize, max_users: usize },
+    ResumeToken { token: String, grace_period_secs: u64 },
+    Resumed { room_name: String, username: String },
+    Waitlisted { room_name: String, position: usize },
+    UserMessage {
+        /// Globally unique message ID.
+        id: String,
+        /// Position in the room's message order, starting at 1.
+        seq: u64,
+        /// Server time the message was accepted, in RFC 3339.
+        timestamp: String,
+        username: String,
+        content: String,
+        /// Replayed from the room's recent history rather than sent live.
+        #[serde(default)]
+        history: bool,
+        /// When the message was last edited, for replayed history.
+        #[serde(default)]
+        edited_at: Option<String>,
+        /// The message this one replies to.
+        #[serde(default)]
+        reply_to: Option<Quote>,
+        /// Reaction counts by emoji, for replayed history.
+        #[serde(default)]
+        reactions: BTreeMap<String, usize>,
+        /// Room members the message mentions as `@name`, for live messages.
+        #[serde(default)]
+        mentions: Vec<String>,
+        /// Set on the copy sent to each mentioned user.
+        #[serde(default)]
+        mentioned: bool,
+        /// When an ephemeral message disappears, in RFC 3339.
+        #[serde(default)]
+        expires_at: Option<String>,
+    },
+    RoomInfo {
+        room_name: String,
+        users: Vec<RoomUser>,
+        current_count: usize,
+        max_users: usize,
+        #[serde(default)]
+        spectators: Vec<String>,
+        #[serde(default)]
+        max_spectators: usize,
+        #[serde(default)]
+        announcement_only: bool,
+        #[serde(default)]
+        moderators: Vec<String>,
+        #[serde(default)]
+        posters: Vec<String>,
+        #[serde(default)]
+        require_login: bool,
+        /// Pinned messages, in the order they were pinned.
+        #[serde(default)]
+        pinned: Vec<StoredMessage>,
+    },
+    MaxSpectatorsSet { max_spectators: usize },
+    PermissionsUpdated { announcement_only: bool, moderators: Vec<String>, posters: Vec<String> },
+    NickChanged { old: String, new: String },
+    PresenceChanged { username: String, presence: Presence, status: Option<String> },
+    UserTyping { username: String, active: bool },
+    PrivateMessage { from: String, to: String, content: String },
+    History { room_id: String, messages: Vec<StoredMessage> },
+    SearchResults { room_id: String, query: String, messages: Vec<StoredMessage> },
+    Thread { room_id: String, root_id: String, messages: Vec<StoredMessage> },
+    MessageEdited { id: String, seq: u64, content: String, edited_at: String, by: String },
+    MessageDeleted { id: String, seq: u64, by: String },
+    /// An ephemeral message's time is up; clients should stop showing it.
+    MessageExpired { id: String, seq: u64 },
+    /// Reaction counts by emoji after someone reacts or takes a reaction back.
+    ReactionsUpdated { message_id: String, seq: u64, reactions: BTreeMap<String, usize> },
+    /// The room's pinned messages, sent after the history replay on join.
+    PinnedMessages { room_id: String, messages: Vec<StoredMessage> },
+    MessagePinned { message: StoredMessage, by: String },
+    MessageUnpinned { id: String, seq: u64, by: String },
+    PollStarted { poll: PollSummary },
+    /// A poll's new tally after someone votes.
+    PollUpdated { poll: PollSummary },
+    /// A poll's final result. `by` is `None` when it reached its deadline.
+    PollClosed { poll: PollSummary, by: Option<String> },
+    /// The room's open polls, sent after the pinned messages on join.
+    OpenPolls { room_id: String, polls: Vec<PollSummary> },
+    MessageScheduled { message: ScheduledSummary },
+    /// Your pending scheduled messages in the room, soonest first.
+    ScheduledMessages { room_id: String, messages: Vec<ScheduledSummary> },
+    ScheduledMessageCancelled { id: u64 },
     UserLeft { username: String },
-    Error { message: String },
+    Error {
+        message: String,
+        #[serde(default)]
+        kind: ErrorKind,
+    },
+}
+
+/// Machine-readable category attached to `Message::Error` so clients can
+/// react without parsing the message text.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+enum ErrorKind {
+    #[default]
+    Other,
+    PermissionDenied,
+    UsernameTaken,
+    InvalidUsername,
+    InvalidRoomName,
+    InvalidMessage,
+    AuthenticationRequired,
+    AuthenticationFailed,
+    RegistrationFailed,
+    ResumeFailed,
+    UserNotFound,
+    MessageNotFound,
+    PollNotFound,
+}
+
+/// Availability a user advertises to the rooms they're in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Presence {
+    #[default]
+    Online,
+    Away,
+    Busy,
+    Dnd,
+}
+
+/// A participant as listed in `RoomInfo`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RoomUser {
+    username: String,
+    #[serde(default)]
+    presence: Presence,
+    #[serde(default)]
+    status: Option<String>,
+}
+
+/// A poll as sent to clients, with a vote count for each option.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PollSummary {
+    /// Position in the room's polls, starting at 1.
+    id: u64,
+    question: String,
+    options: Vec<String>,
+    /// Votes for each option, in the same order as `options`.
+    votes: Vec<usize>,
+    created_by: String,
+    /// When the poll closes by itself, in RFC 3339.
+    #[serde(default)]
+    closes_at: Option<String>,
+}
+
+/// A pending scheduled message, as shown to its author.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ScheduledSummary {
+    /// Position in the room's scheduled messages, starting at 1.
+    id: u64,
+    content: String,
+    /// When the message will be posted, in RFC 3339.
+    send_at: String,
 }
 
+/// Spectator cap used when `CreateRoom` doesn't specify one.
+const DEFAULT_MAX_SPECTATORS: usize = 10;
+
+fn default_max_spectators() -> usize {
+    DEFAULT_MAX_SPECTATORS
+}
+
+/// Recent messages a room keeps for late joiners when `CreateRoom` doesn't
+/// say, and the most it may ask for.
+const DEFAULT_HISTORY_SIZE: usize = 50;
+const MAX_HISTORY_SIZE: usize = 200;
+
+/// How long recent messages are kept by default; 0 keeps them regardless of age.
+const DEFAULT_HISTORY_MAX_AGE_SECS: u64 = 60 * 60;
+
+fn default_history_size() -> usize {
+    DEFAULT_HISTORY_SIZE
+}
+
+fn default_history_max_age_secs() -> u64 {
+    DEFAULT_HISTORY_MAX_AGE_SECS
+}
+
+/// Messages returned by `GetHistory` when no limit is given, and the most
+/// one request may ask for.
+const DEFAULT_HISTORY_PAGE: usize = 50;
+const MAX_HISTORY_PAGE: usize = 200;
+
+fn default_history_page() -> usize {
+    DEFAULT_HISTORY_PAGE
+}
+
+/// Matches returned by `SearchHistory` when no limit is given, and the most
+/// one request may ask for.
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+const MAX_SEARCH_LIMIT: usize = 100;
+
+fn default_search_limit() -> usize {
+    DEFAULT_SEARCH_LIMIT
+}
+
+/// Different emoji one message can collect, so reactions can't be used to
+/// grow a room's memory without bound.
+const MAX_REACTIONS_PER_MESSAGE: usize = 20;
+
+/// Messages a room can have pinned at once. Pins are meant for the few
+/// things everyone needs to see.
+const MAX_PINNED_MESSAGES: usize = 10;
+
+/// Longest an ephemeral message can stay up, in seconds.
+const MAX_MESSAGE_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// Polls a room can have open at once.
+const MAX_OPEN_POLLS: usize = 5;
+
+/// Furthest away a poll's deadline can be, in seconds.
+const MAX_POLL_DURATION_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Furthest ahead a message can be scheduled, in seconds.
+const MAX_SCHEDULE_AHEAD_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// Messages one user can have waiting in a room at once.
+const MAX_SCHEDULED_PER_USER: usize = 10;
+
 // ============================================================================
 // Data Structures
 // ============================================================================
 
 type Clients = Arc<Mutex<HashMap<String, Client>>>;
 type Rooms = Arc<RwLock<HashMap<String, Room>>>;
+type Accounts = Arc<std::sync::Mutex<AccountStore>>;
+type MessageLog = Arc<std::sync::Mutex<Box<dyn MessageStore>>>;
+
+/// SQLite file holding registered accounts, relative to the working directory.
+const ACCOUNTS_DB_PATH: &str = "accounts.db";
+
+/// Environment variable choosing where chat history is persisted: `sqlite`
+/// (the default), `file` or `memory`.
+///
+/// Rooms only exist in memory and get a new ID every time they're created,
+/// so history written before a restart belongs to rooms nobody can join
+/// again. It can only be read back during the run that wrote it, and is
+/// kept on disk until retention removes it.
+const HISTORY_BACKEND_ENV: &str = "RUST_CHAT_HISTORY_BACKEND";
+const DEFAULT_HISTORY_BACKEND: &str = "sqlite";
+
+/// Files used by the `sqlite` and `file` history backends, relative to the
+/// working directory.
+const HISTORY_DB_PATH: &str = "history.db";
+const HISTORY_FILE_PATH: &str = "history.jsonl";
+
+/// Environment variables limiting how much persisted history is kept: the
+/// age in days after which messages are deleted, and the number of newest
+/// messages kept per room. `0` turns a limit off.
+const HISTORY_MAX_AGE_ENV: &str = "RUST_CHAT_HISTORY_MAX_AGE_DAYS";
+const DEFAULT_HISTORY_MAX_AGE_DAYS: u64 = 30;
+const HISTORY_MAX_PER_ROOM_ENV: &str = "RUST_CHAT_HISTORY_MAX_PER_ROOM";
+const DEFAULT_HISTORY_MAX_PER_ROOM: u64 = 10_000;
+
+/// How often the retention limits are enforced.
+const HISTORY_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long a dropped connection's seat is held for it to resume.
+const RESUME_GRACE_PERIOD: Duration = Duration::from_secs(60);
+
+/// Minimum time between forwarded "started typing" notices per client.
+const TYPING_RATE_LIMIT: Duration = Duration::from_secs(2);
 
 struct Client {
     username: String,
     room: Option<String>,
+    waiting_for: Option<String>,
+    spectator: bool,
+    /// Registered account this connection has logged in as, if any.
+    account: Option<String>,
+    /// The client ID this session first connected with. It's kept when the
+    /// session is resumed under a new client ID.
+    session: String,
+    /// Set while the connection is gone but its seat is held for resumption;
+    /// identifies the hold so a stale expiry timer can tell it was resumed.
+    held: Option<Uuid>,
+    presence: Presence,
+    /// Short custom status line shown next to the presence.
+    status: Option<String>,
+    /// When the last "started typing" notice was forwarded, cleared once the
+    /// client says it stopped.
+    last_typing: Option<Instant>,
+    /// Peer address of the current connection. Only used for logging and
+    /// rate limiting; clients are identified by their session ID.
+    addr: SocketAddr,
     socket: Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
 }
 
+impl Client {
+    /// Identifies who wrote a message, so only they can change it later:
+    /// the account for logged-in users, otherwise the session. Unlike the
+    /// username it can't be taken over by someone else.
+    fn author_id(&self) -> String {
+        match &self.account {
+            Some(account) => format!("account:{}", account.to_lowercase()),
+            None => format!("session:{}", self.session),
+        }
+    }
+}
+
 struct Room {
     id: Uuid,
     name: String,
+    owner: String,
     clients: Vec<String>,
     max_users: usize,
+    waitlist: VecDeque<String>,
+    spectators: Vec<String>,
+    max_spectators: usize,
+    announcement_only: bool,
+    /// Granted to a member's `Client::author_id` rather than their name, which
+    /// anyone could claim once they leave. Each maps to the name they're
+    /// listed under, kept up to date when they change it.
+    moderators: HashMap<String, String>,
+    posters: HashMap<String, String>,
+    require_login: bool,
+    /// Sequence number given to the room's most recent chat message.
+    last_seq: u64,
+    /// Recent chat messages, oldest first, replayed to clients who join.
+    history: VecDeque<ChatRecord>,
+    history_size: usize,
+    /// Messages older than this are no longer replayed; `None` keeps them
+    /// until they're pushed out by newer ones.
+    history_max_age: Option<Duration>,
+    /// Reactions by message ID.
+    reactions: HashMap<String, MessageReactions>,
+    /// Copies of the pinned messages, in the order they were pinned.
+    pinned: Vec<StoredMessage>,
+    /// ID given to the room's most recent poll.
+    last_poll: u64,
+    /// Open polls by ID; closed ones are dropped once their result is sent.
+    polls: BTreeMap<u64, Poll>,
+    /// ID given to the room's most recently scheduled message.
+    last_scheduled: u64,
+    /// Messages waiting to be posted, by ID. Each is removed when it's sent
+    /// or cancelled.
+    scheduled: BTreeMap<u64, ScheduledMessage>,
+}
+
+/// A chat message as kept in a room's recent history.
+struct ChatRecord {
+    message: StoredMessage,
+    /// When the server accepted the message, for age-based expiry.
+    received: Instant,
+}
+
+/// Who has reacted to a message, by emoji. Reactors are kept by author ID,
+/// so leaving and rejoining under another name doesn't give a second one.
+struct MessageReactions {
+    seq: u64,
+    users: BTreeMap<String, HashSet<String>>,
+}
+
+impl MessageReactions {
+    fn tally(&self) -> BTreeMap<String, usize> {
+        self.users.iter().map(|(emoji, users)| (emoji.clone(), users.len())).collect()
+    }
+}
+
+/// An open poll. Votes are kept by author ID, so each user has one however
+/// often they change their name.
+struct Poll {
+    question: String,
+    options: Vec<String>,
+    /// The creator's name, for display.
+    created_by: String,
+    /// The creator's author ID, which lets them close the poll.
+    creator_id: String,
+    closes_at: Option<String>,
+    /// The option each user voted for, by author ID.
+    votes: HashMap<String, usize>,
+}
+
+impl Poll {
+    fn summary(&self, id: u64) -> PollSummary {
+        let mut votes = vec![0; self.options.len()];
+        for &option in self.votes.values() {
+            votes[option] += 1;
+        }
+        PollSummary {
+            id,
+            question: self.question.clone(),
+            options: self.options.clone(),
+            votes,
+            created_by: self.created_by.clone(),
+            closes_at: self.closes_at.clone(),
+        }
+    }
+}
+
+/// A message waiting for its time to be posted.
+struct ScheduledMessage {
+    /// Who scheduled it, as from `Client::author_id`. Only they can list and
+    /// cancel it, and it's only sent if they're still in the room.
+    author_id: String,
+    content: String,
+    send_at: DateTime<Utc>,
+}
+
+impl ScheduledMessage {
+    fn summary(&self, id: u64) -> ScheduledSummary {
+        ScheduledSummary {
+            id,
+            content: self.content.clone(),
+            send_at: self.send_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+        }
+    }
+}
+
+fn user_message(
+    message: StoredMessage,
+    history: bool,
+    mentions: Vec<String>,
+    mentioned: bool,
+    expires_at: Option<String>,
+) -> Message {
+    let StoredMessage { id, seq, timestamp, username, content, edited_at, reply_to, reactions, .. } = message;
+    Message::UserMessage {
+        id,
+        seq,
+        timestamp,
+        username,
+        content,
+        history,
+        edited_at,
+        reply_to,
+        reactions,
+        mentions,
+        mentioned,
+        expires_at,
+    }
+}
+
+/// What a client asked to post in `Chat`.
+struct ChatRequest<'a> {
+    content: &'a str,
+    reply_to: Option<&'a str>,
+    ttl_seconds: Option<u64>,
+}
+
+/// Settings chosen by the creator in `CreateRoom`.
+struct RoomConfig {
+    max_users: usize,
+    max_spectators: usize,
+    announcement_only: bool,
+    require_login: bool,
+    history_size: usize,
+    history_max_age_secs: u64,
+}
+
+impl Room {
+    fn is_moderator(&self, client_id: &str, author_id: &str) -> bool {
+        self.owner == client_id || self.moderators.contains_key(author_id)
+    }
+
+    fn can_post(&self, client_id: &str, author_id: &str) -> bool {
+        !self.announcement_only
+            || self.is_moderator(client_id, author_id)
+            || self.posters.contains_key(author_id)
+    }
+
+    fn is_expired(&self, record: &ChatRecord) -> bool {
+        matches!(self.history_max_age, Some(max_age) if record.received.elapsed() > max_age)
+    }
+
+    /// Adds a message to the history, dropping the oldest ones once the
+    /// room's size or age limit is exceeded.
+    fn remember(&mut self, record: ChatRecord) {
+        if self.history_size == 0 {
+            return;
+        }
+
+        self.history.push_back(record);
+        while self.history.len() > self.history_size
+            || self.history.front().is_some_and(|oldest| self.is_expired(oldest))
+        {
+            self.history.pop_front();
+        }
+    }
+
+    /// The messages a joining client should be shown, oldest first.
+    fn recent_history(&self) -> Vec<Message> {
+        self.history.iter()
+            .filter(|record| !self.is_expired(record))
+            .map(|record| {
+                let mut message = record.message.clone();
+                message.reactions = self.reaction_tally(&message.id);
+                user_message(message, true, Vec::new(), false, None)
+            })
+            .collect()
+    }
+
+    /// Reaction counts by emoji for a message, empty if it has none.
+    fn reaction_tally(&self, message_id: &str) -> BTreeMap<String, usize> {
+        self.reactions.get(message_id).map(MessageReactions::tally).unwrap_or_default()
+    }
+
+    /// The pinned messages with their current reaction counts.
+    fn pinned_messages(&self) -> Vec<StoredMessage> {
+        let mut pinned = self.pinned.clone();
+        self.add_reactions(&mut pinned);
+        pinned
+    }
+
+    fn open_polls(&self) -> Vec<PollSummary> {
+        self.polls.iter().map(|(&id, poll)| poll.summary(id)).collect()
+    }
+
+    /// Every copy of a message the room keeps in memory: its recent history
+    /// and its pins.
+    fn kept_messages_mut(&mut self) -> impl Iterator<Item = &mut StoredMessage> {
+        self.history.iter_mut().map(|record| &mut record.message).chain(self.pinned.iter_mut())
+    }
+
+    /// Fills in the reaction counts of messages loaded from the store.
+    fn add_reactions(&self, messages: &mut [StoredMessage]) {
+        for message in messages {
+            message.reactions = self.reaction_tally(&message.id);
+        }
+    }
 }
 
 // ============================================================================
@@ -61,22 +661,81 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
     let rooms: Rooms = Arc::new(RwLock::new(HashMap::new()));
+    let accounts: Accounts = Arc::new(std::sync::Mutex::new(
+        AccountStore::open(ACCOUNTS_DB_PATH).map_err(|e| e.to_string())?,
+    ));
+    println!("Account database: {}", ACCOUNTS_DB_PATH);
+
+    let backend = std::env::var(HISTORY_BACKEND_ENV).unwrap_or_else(|_| DEFAULT_HISTORY_BACKEND.to_string());
+    let history_path = if backend == "file" { HISTORY_FILE_PATH } else { HISTORY_DB_PATH };
+    let message_log: MessageLog = Arc::new(std::sync::Mutex::new(
+        history::open(&backend, history_path).map_err(|e| e.to_string())?,
+    ));
+    match backend.as_str() {
+        "memory" => println!("Message history: in memory"),
+        _ => println!("Message history: {} ({}, readable until the server restarts)", history_path, backend),
+    }
+
+    let retention = history_retention();
+    println!(
+        "History retention: {}, {}",
+        retention.max_age.map_or("no age limit".to_string(), |age| format!("{} days", age.as_secs() / (24 * 60 * 60))),
+        retention.max_per_room.map_or("no per-room limit".to_string(), |max| format!("{} messages per room", max))
+    );
+
+    // Enforce retention now and then periodically
+    let pruning_log = Arc::clone(&message_log);
+    let retention = Arc::new(retention);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(HISTORY_PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let retention = Arc::clone(&retention);
+            match with_message_log(&pruning_log, move |store| store.prune(&retention)).await {
+                Ok(0) => {}
+                Ok(removed) => println!("Pruned {} messages from history", removed),
+                Err(e) => eprintln!("Message history error: {}", e),
+            }
+        }
+    });
 
     loop {
-        let (socket, addr) = listener.accept().await?;
-        let client_id = addr.to_string();
+        let (socket, peer_addr) = listener.accept().await?;
+        // Opaque session ID, so identity isn't tied to the transport endpoint
+        let client_id = Uuid::new_v4().to_string();
 
         let clients = Arc::clone(&clients);
         let rooms = Arc::clone(&rooms);
+        let accounts = Arc::clone(&accounts);
+        let message_log = Arc::clone(&message_log);
 
         tokio::spawn(async move {
-            if let Err(e) = handle_client(socket, client_id, clients, rooms).await {
+            if let Err(e) = handle_client(socket, client_id, peer_addr, clients, rooms, accounts, message_log).await {
                 eprintln!("Error handling client: {}", e);
             }
         });
     }
 }
 
+/// Reads a history limit from the environment, falling back to the default
+/// if it's unset or not a number. `0` means no limit.
+fn history_limit(env: &str, default: u64) -> Option<u64> {
+    let value = std::env::var(env)
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(default);
+    (value > 0).then_some(value)
+}
+
+fn history_retention() -> Retention {
+    Retention {
+        max_age: history_limit(HISTORY_MAX_AGE_ENV, DEFAULT_HISTORY_MAX_AGE_DAYS)
+            .map(|days| Duration::from_secs(days.saturating_mul(24 * 60 * 60))),
+        max_per_room: history_limit(HISTORY_MAX_PER_ROOM_ENV, DEFAULT_HISTORY_MAX_PER_ROOM)
+            .map(|max| usize::try_from(max).unwrap_or(usize::MAX)),
+    }
+}
+
 // ============================================================================
 // Client Handler
 // ============================================================================
@@ -84,13 +743,37 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 async fn handle_client(
     socket: TcpStream,
     client_id: String,
+    addr: SocketAddr,
     clients: Clients,
     rooms: Rooms,
+    accounts: Accounts,
+    message_log: MessageLog,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let (reader, writer) = socket.into_split();
     let writer = Arc::new(Mutex::new(writer));
     let mut reader = BufReader::new(reader);
 
+    // Track the connection from the start so a login can be attached to it
+    {
+        let mut clients_guard = clients.lock().await;
+        clients_guard.insert(client_id.clone(), Client {
+            username: String::new(),
+            room: None,
+            waiting_for: None,
+            spectator: false,
+            account: None,
+            session: client_id.clone(),
+            held: None,
+            presence: Presence::default(),
+            status: None,
+            last_typing: None,
+            addr,
+            socket: Arc::clone(&writer),
+        });
+    }
+
+    println!("Client {} connected from {}", client_id, addr);
+
     send_message(&writer, &Message::Connected).await?;
 
     let mut line = String::new();
@@ -104,7 +787,7 @@ async fn handle_client(
         }
 
         if let Ok(message) = serde_json::from_str::<Message>(&line) {
-            handle_message(&message, &client_id, &writer, &clients, &rooms).await?;
+            handle_message(&message, &client_id, &writer, &clients, &rooms, &accounts, &message_log).await?;
         }
     }
 
@@ -122,20 +805,138 @@ async fn handle_message(
     writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
     clients: &Clients,
     rooms: &Rooms,
+    accounts: &Accounts,
+    message_log: &MessageLog,
 ) -> Result<(), Box<dyn std::error::Error>> {
     match message {
-        Message::CreateRoom { room_name, max_users } => {
-            handle_create_room(room_name, *max_users, writer, rooms).await?;
+        Message::CreateRoom {
+            room_name,
+            max_users,
+            max_spectators,
+            announcement_only,
+            require_login,
+            history_size,
+            history_max_age_secs,
+        } => {
+            let config = RoomConfig {
+                max_users: *max_users,
+                max_spectators: *max_spectators,
+                announcement_only: *announcement_only,
+                require_login: *require_login,
+                history_size: *history_size,
+                history_max_age_secs: *history_max_age_secs,
+            };
+            handle_create_room(room_name, config, client_id, writer, clients, rooms).await?;
         }
-        Message::JoinRoom { room_id, username } => {
-            handle_join_room(room_id, username, client_id, writer, clients, rooms).await?;
+        Message::JoinRoom { room_id, username, wait, spectate } => {
+            let Some(username) = resolve_username(username, client_id, writer, clients, accounts).await? else {
+                return Ok(());
+            };
+            let username = username.as_str();
+
+            // A client is in at most one room or waitlist at a time. Asking
+            // again for the room it's queued for keeps its place in line
+            if *spectate || !is_waiting_for(client_id, room_id, clients).await {
+                leave_room(client_id, clients, rooms).await?;
+            }
+
+            if *spectate {
+                handle_spectate_room(room_id, username, client_id, writer, clients, rooms).await?;
+            } else {
+                handle_join_room(room_id, username, *wait, client_id, writer, clients, rooms).await?;
+            }
+        }
+        Message::LeaveRoom => {
+            leave_room(client_id, clients, rooms).await?;
         }
-        Message::Chat { content } => {
-            handle_chat(content, client_id, clients, rooms).await?;
+        Message::Chat { content, reply_to, ttl_seconds } => {
+            let request = ChatRequest { content, reply_to: reply_to.as_deref(), ttl_seconds: *ttl_seconds };
+            handle_chat(request, client_id, writer, clients, rooms, message_log).await?;
         }
         Message::GetRoomInfo => {
             handle_get_room_info(client_id, writer, clients, rooms).await?;
         }
+        Message::SetMaxSpectators { max_spectators } => {
+            handle_set_max_spectators(*max_spectators, client_id, writer, clients, rooms).await?;
+        }
+        Message::SetAnnouncementOnly { enabled } => {
+            let change = PermissionChange::AnnouncementOnly(*enabled);
+            handle_permission_change(change, client_id, writer, clients, rooms).await?;
+        }
+        Message::SetModerator { username, enabled } => {
+            let change = PermissionChange::Moderator(username, *enabled);
+            handle_permission_change(change, client_id, writer, clients, rooms).await?;
+        }
+        Message::SetPoster { username, enabled } => {
+            let change = PermissionChange::Poster(username, *enabled);
+            handle_permission_change(change, client_id, writer, clients, rooms).await?;
+        }
+        Message::Register { username, password } => {
+            handle_register(username, password, client_id, writer, clients, accounts).await?;
+        }
+        Message::Login { username, password } => {
+            handle_login(username, password, client_id, writer, clients, accounts).await?;
+        }
+        Message::Resume { token } => {
+            handle_resume(token, client_id, writer, clients, rooms).await?;
+        }
+        Message::ChangeNick { username } => {
+            handle_change_nick(username, client_id, writer, clients, rooms, accounts).await?;
+        }
+        Message::SetPresence { presence, status } => {
+            handle_set_presence(*presence, status.as_deref(), client_id, writer, clients, rooms).await?;
+        }
+        Message::Typing { active } => {
+            handle_typing(*active, client_id, clients, rooms).await?;
+        }
+        Message::DirectMessage { to, content } => {
+            handle_direct_message(to, content, client_id, writer, clients, rooms).await?;
+        }
+        Message::GetHistory { room_id, before, limit } => {
+            handle_get_history(room_id, *before, *limit, client_id, writer, rooms, message_log).await?;
+        }
+        Message::SearchHistory { room_id, query, limit } => {
+            handle_search_history(room_id, query, *limit, client_id, writer, rooms, message_log).await?;
+        }
+        Message::GetThread { room_id, message_id } => {
+            handle_get_thread(room_id, message_id, client_id, writer, rooms, message_log).await?;
+        }
+        Message::EditMessage { message_id, content } => {
+            handle_edit_message(message_id, content, client_id, writer, clients, rooms, message_log).await?;
+        }
+        Message::DeleteMessage { message_id } => {
+            handle_delete_message(message_id, client_id, writer, clients, rooms, message_log).await?;
+        }
+        Message::React { message_id, emoji } => {
+            handle_react(message_id, emoji, client_id, writer, clients, rooms, message_log).await?;
+        }
+        Message::Unreact { message_id, emoji } => {
+            handle_unreact(message_id, emoji, client_id, writer, clients, rooms).await?;
+        }
+        Message::PinMessage { message_id } => {
+            handle_pin_message(message_id, client_id, writer, clients, rooms, message_log).await?;
+        }
+        Message::UnpinMessage { message_id } => {
+            handle_unpin_message(message_id, client_id, writer, clients, rooms).await?;
+        }
+        Message::CreatePoll { question, options, duration_secs } => {
+            handle_create_poll(question, options, *duration_secs, client_id, writer, clients, rooms).await?;
+        }
+        Message::Vote { poll_id, option } => {
+            handle_vote(*poll_id, *option, client_id, writer, clients, rooms).await?;
+        }
+        Message::ClosePoll { poll_id } => {
+            handle_close_poll(*poll_id, client_id, writer, clients, rooms).await?;
+        }
+        Message::ScheduleMessage { content, send_at } => {
+            handle_schedule_message(content, send_at, client_id, writer, clients, rooms, message_log).await?;
+        }
+        Message::GetScheduledMessages => {
+            handle_get_scheduled_messages(client_id, writer, clients, rooms).await?;
+        }
+        Message::CancelScheduledMessage { id } => {
+            handle_cancel_scheduled_message(*id, client_id, writer, clients, rooms).await?;
+        }
         _ => {}
     }
     Ok(())
@@ -143,10 +944,38 @@ async fn handle_message(
 
 async fn handle_create_room(
     room_name: &str,
-    max_users: usize,
+    config: RoomConfig,
+    client_id: &str,
     writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
     rooms: &Rooms,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let RoomConfig {
+        max_users,
+        max_spectators,
+        announcement_only,
+        require_login,
+        history_size,
+        history_max_age_secs,
+    } = config;
+
+    if let Err(message) = ROOM_NAME_RULES.validate(room_name) {
+        send_message(writer, &Message::Error {
+            message,
+            kind: ErrorKind::InvalidRoomName,
+        }).await?;
+        return Ok(());
+    }
+
+    // Otherwise the creator would be locked out of their own room
+    if require_login && !is_logged_in(client_id, clients).await {
+        send_message(writer, &Message::Error {
+            message: "Log in to create a room that requires an account".to_string(),
+            kind: ErrorKind::AuthenticationRequired,
+        }).await?;
+        return Ok(());
+    }
+
     let mut rooms_guard = rooms.write().await;
 
     // Check if room name already exists
@@ -155,6 +984,7 @@ async fn handle_create_room(
     if name_exists {
         send_message(writer, &Message::Error {
             message: "Room name already exists".to_string(),
+            kind: ErrorKind::Other,
         }).await?;
         return Ok(());
     }
@@ -162,6 +992,15 @@ async fn handle_create_room(
     if max_users < 2 {
         send_message(writer, &Message::Error {
             message: "Room must allow at least 2 users".to_string(),
+            kind: ErrorKind::Other,
+        }).await?;
+        return Ok(());
+    }
+
+    if history_size > MAX_HISTORY_SIZE {
+        send_message(writer, &Message::Error {
+            message: format!("Rooms can keep at most {} recent messages", MAX_HISTORY_SIZE),
+            kind: ErrorKind::Other,
         }).await?;
         return Ok(());
     }
@@ -172,16 +1011,43 @@ async fn handle_create_room(
     rooms_guard.insert(room_id_str.clone(), Room {
         id: room_id,
         name: room_name.to_string(),
+        owner: client_id.to_string(),
         clients: Vec::new(),
         max_users,
+        waitlist: VecDeque::new(),
+        spectators: Vec::new(),
+        max_spectators,
+        announcement_only,
+        moderators: HashMap::new(),
+        posters: HashMap::new(),
+        require_login,
+        last_seq: 0,
+        history: VecDeque::new(),
+        history_size,
+        history_max_age: (history_max_age_secs > 0).then(|| Duration::from_secs(history_max_age_secs)),
+        reactions: HashMap::new(),
+        pinned: Vec::new(),
+        last_poll: 0,
+        polls: BTreeMap::new(),
+        last_scheduled: 0,
+        scheduled: BTreeMap::new(),
     });
 
-    println!("Room '{}' created with ID: {} (max {} users)", room_name, room_id_str, max_users);
+    println!(
+        "Room '{}' created with ID: {} (max {} users, {} spectators{}{})",
+        room_name,
+        room_id_str,
+        max_users,
+        max_spectators,
+        if announcement_only { ", announcement-only" } else { "" },
+        if require_login { ", login required" } else { "" }
+    );
 
     send_message(writer, &Message::RoomCreated {
         room_name: room_name.to_string(),
         room_id: room_id_str,
         max_users,
+        max_spectators,
     }).await?;
 
     Ok(())
@@ -190,56 +1056,95 @@ async fn handle_create_room(
 async fn handle_join_room(
     room_id: &str,
     username: &str,
+    wait: bool,
     client_id: &str,
     writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
     clients: &Clients,
     rooms: &Rooms,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // Check room exists and has space
-    let room_info = {
-        let rooms_guard = rooms.read().await;
-        rooms_guard.get(room_id).map(|room| {
-            (room.name.clone(), room.max_users, room.clients.len(), room.clients.len() < room.max_users)
-        })
-    };
-
-    let Some((room_name, max_users, current_users, can_join)) = room_info else {
+    if !room_exists(room_id, rooms).await {
         send_message(writer, &Message::Error {
             message: "Invalid room ID".to_string(),
+            kind: ErrorKind::Other,
         }).await?;
         return Ok(());
-    };
+    }
 
-    if !can_join {
-        send_message(writer, &Message::Error {
-            message: format!("Room is full ({}/{} users)", current_users, max_users),
-        }).await?;
+    if !check_username(room_id, username, client_id, writer, clients, rooms).await? {
         return Ok(());
     }
 
-    // Add client to room
-    {
-        let mut rooms_guard = rooms.write().await;
-        if let Some(room) = rooms_guard.get_mut(room_id) {
-            room.clients.push(client_id.to_string());
-        }
+    let admission = admit_to_room(room_id, username, client_id, writer, clients, rooms).await?;
+    match admission {
+        Admission::Seated => return Ok(()),
+        Admission::NameTaken => return send_username_taken(username, writer).await,
+        Admission::Full => {}
     }
 
-    // Register client
-    {
-        let mut clients_guard = clients.lock().await;
-        clients_guard.insert(client_id.to_string(), Client {
-            username: username.to_string(),
-            room: Some(room_id.to_string()),
-            socket: Arc::clone(writer),
-        });
+    if wait {
+        return join_waitlist(room_id, username, client_id, writer, clients, rooms).await;
     }
 
-    // Get updated user count
-    let user_count = {
+    let (current_users, max_users) = {
         let rooms_guard = rooms.read().await;
-        rooms_guard.get(room_id).map(|r| r.clients.len()).unwrap_or(1)
+        rooms_guard.get(room_id).map(|room| (room.clients.len(), room.max_users)).unwrap_or_default()
+    };
+    send_message(writer, &Message::Error {
+        message: format!("Room is full ({}/{} users)", current_users, max_users),
+        kind: ErrorKind::Other,
+    }).await?;
+    Ok(())
+}
+
+/// Outcome of trying to seat a client in a room.
+enum Admission {
+    Seated,
+    /// The room is full or has gone.
+    Full,
+    /// Someone else took the name since it was checked.
+    NameTaken,
+}
+
+async fn admit_to_room(
+    room_id: &str,
+    username: &str,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<Admission, Box<dyn std::error::Error>> {
+    // Check for a free seat and the name again, and take the seat, under one
+    // lock so two joins can't both get the last seat or the same name
+    let room_info = {
+        let mut rooms_guard = rooms.write().await;
+        let mut clients_guard = clients.lock().await;
+        let Some(room) = rooms_guard.get_mut(room_id) else {
+            return Ok(Admission::Full);
+        };
+        if room.clients.len() >= room.max_users {
+            return Ok(Admission::Full);
+        }
+        if is_name_in_use(room, &clients_guard, username, client_id) {
+            return Ok(Admission::NameTaken);
+        }
+
+        room.clients.push(client_id.to_string());
+        if let Some(client) = clients_guard.get_mut(client_id) {
+            client.username = username.to_string();
+            client.room = Some(room_id.to_string());
+            client.waiting_for = None;
+            client.spectator = false;
+        }
+        (
+            room.name.clone(),
+            room.clients.len(),
+            room.max_users,
+            room.recent_history(),
+            room.pinned_messages(),
+            room.open_polls(),
+        )
     };
+    let (room_name, user_count, max_users, history, pinned, polls) = room_info;
 
     // Notify all clients in the room
     let join_msg = Message::JoinedRoom {
@@ -249,71 +1154,2203 @@ async fn handle_join_room(
 
     broadcast_to_room(clients, rooms, room_id, &join_msg, Some(client_id)).await?;
     send_message(writer, &join_msg).await?;
+    for message in &history {
+        send_message(writer, message).await?;
+    }
+    send_pinned_messages(room_id, pinned, writer).await?;
+    send_open_polls(room_id, polls, writer).await?;
+    send_resume_token(client_id, room_id, writer).await?;
 
     println!("User '{}' joined room '{}' ({}/{} users)", username, room_name, user_count, max_users);
 
-    Ok(())
+    Ok(Admission::Seated)
 }
 
-async fn handle_chat(
-    content: &str,
+async fn handle_spectate_room(
+    room_id: &str,
+    username: &str,
     client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
     clients: &Clients,
     rooms: &Rooms,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let (username, room_id) = {
-        let clients_guard = clients.lock().await;
-        clients_guard.get(client_id).map(|client| {
-            (client.username.clone(), client.room.clone())
-        }).unwrap_or_default()
+    if !check_username(room_id, username, client_id, writer, clients, rooms).await? {
+        return Ok(());
+    }
+
+    // Spectators don't take a seat, so only the spectator cap applies. The
+    // name is checked again under the lock in case someone just took it
+    let result = {
+        let mut rooms_guard = rooms.write().await;
+        let mut clients_guard = clients.lock().await;
+        match rooms_guard.get_mut(room_id) {
+            None => Err(("Invalid room ID".to_string(), ErrorKind::Other)),
+            Some(room) if room.max_spectators == 0 => {
+                Err(("Room is not accepting spectators".to_string(), ErrorKind::Other))
+            }
+            Some(room) if room.spectators.len() >= room.max_spectators => Err((
+                format!(
+                    "Spectator limit reached ({}/{} spectators)",
+                    room.spectators.len(),
+                    room.max_spectators
+                ),
+                ErrorKind::Other,
+            )),
+            Some(room) if is_name_in_use(room, &clients_guard, username, client_id) => Err((
+                format!("Username '{}' is already taken in this room", username),
+                ErrorKind::UsernameTaken,
+            )),
+            Some(room) => {
+                room.spectators.push(client_id.to_string());
+                if let Some(client) = clients_guard.get_mut(client_id) {
+                    client.username = username.to_string();
+                    client.room = Some(room_id.to_string());
+                    client.waiting_for = None;
+                    client.spectator = true;
+                }
+                Ok((
+                    room.name.clone(),
+                    room.spectators.len(),
+                    room.max_spectators,
+                    room.recent_history(),
+                    room.pinned_messages(),
+                    room.open_polls(),
+                ))
+            }
+        }
     };
 
-    if let Some(room_id) = room_id {
-        let chat_msg = Message::UserMessage {
-            username,
-            content: content.to_string(),
-        };
-        broadcast_to_room(clients, rooms, &room_id, &chat_msg, None).await?;
+    let (room_name, spectator_count, max_spectators, history, pinned, polls) = match result {
+        Ok(info) => info,
+        Err((message, kind)) => {
+            send_message(writer, &Message::Error { message, kind }).await?;
+            return Ok(());
+        }
+    };
+
+    // Spectators watch quietly: only they are told about the join
+    send_message(writer, &Message::JoinedRoom {
+        room_name: room_name.clone(),
+        username: username.to_string(),
+    }).await?;
+    for message in &history {
+        send_message(writer, message).await?;
     }
+    send_pinned_messages(room_id, pinned, writer).await?;
+    send_open_polls(room_id, polls, writer).await?;
+    send_resume_token(client_id, room_id, writer).await?;
+
+    println!(
+        "User '{}' is spectating room '{}' ({}/{} spectators)",
+        username, room_name, spectator_count, max_spectators
+    );
 
     Ok(())
 }
 
-async fn handle_get_room_info(
+async fn handle_chat(
+    request: ChatRequest<'_>,
     client_id: &str,
     writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
     clients: &Clients,
     rooms: &Rooms,
+    message_log: &MessageLog,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let room_id = {
+    let (username, author_id, room_id, spectator) = {
         let clients_guard = clients.lock().await;
-        clients_guard.get(client_id).and_then(|c| c.room.clone())
+        clients_guard.get(client_id).map(|client| {
+            (client.username.clone(), client.author_id(), client.room.clone(), client.spectator)
+        }).unwrap_or_default()
     };
 
-    let Some(room_id) = room_id else {
+    if spectator {
+        send_message(writer, &Message::Error {
+            message: "Spectators cannot send messages".to_string(),
+            kind: ErrorKind::PermissionDenied,
+        }).await?;
         return Ok(());
-    };
+    }
 
-    let rooms_guard = rooms.read().await;
-    let Some(room) = rooms_guard.get(&room_id) else {
-        return Ok(());
-    };
+    if let Some(room_id) = room_id {
+        let can_post = {
+            let rooms_guard = rooms.read().await;
+            rooms_guard.get(&room_id).is_some_and(|room| room.can_post(client_id, &author_id))
+        };
 
-    let users: Vec<String> = {
-        let clients_guard = clients.lock().await;
-        room.clients.iter()
-            .filter_map(|id| clients_guard.get(id).map(|c| c.username.clone()))
-            .collect()
-    };
+        if !can_post {
+            send_message(writer, &Message::Error {
+                message: "Only moderators and approved posters can post in this room".to_string(),
+                kind: ErrorKind::PermissionDenied,
+            }).await?;
+            return Ok(());
+        }
 
-    send_message(writer, &Message::RoomInfo {
-        room_name: room.name.clone(),
-        users,
-        current_count: room.clients.len(),
-        max_users: room.max_users,
-    }).await?;
+        let content = match sanitize_chat(request.content) {
+            Ok(content) => content,
+            Err(message) => {
+                send_message(writer, &Message::Error { message, kind: ErrorKind::InvalidMessage }).await?;
+                return Ok(());
+            }
+        };
 
-    Ok(())
+        let ttl = match request.ttl_seconds {
+            Some(secs @ 1..=MAX_MESSAGE_TTL_SECS) => Some(Duration::from_secs(secs)),
+            Some(_) => {
+                send_message(writer, &Message::Error {
+                    message: format!(
+                        "Ephemeral messages must last between 1 and {} seconds",
+                        MAX_MESSAGE_TTL_SECS
+                    ),
+                    kind: ErrorKind::InvalidMessage,
+                }).await?;
+                return Ok(());
+            }
+            None => None,
+        };
+
+        let reply_to = match request.reply_to {
+            Some(parent_id) => {
+                let parent = find_requested_message(&room_id, parent_id, writer, rooms, message_log).await?;
+                let Some(parent) = parent else {
+                    return Ok(());
+                };
+                Some(Quote::of(&parent))
+            }
+            None => None,
+        };
+
+        let Some(stored) = stamp_message(&room_id, username, Some(author_id), content, reply_to, ttl.is_some(), rooms).await else {
+            return Ok(());
+        };
+
+        let Some(ttl) = ttl else {
+            store_message(&room_id, &stored, message_log).await;
+            broadcast_chat(client_id, clients, rooms, &room_id, stored, None).await;
+            return Ok(());
+        };
+
+        let expires_at = (Utc::now() + ttl).to_rfc3339_opts(SecondsFormat::Millis, true);
+        let (id, seq) = (stored.id.clone(), stored.seq);
+        broadcast_chat(client_id, clients, rooms, &room_id, stored, Some(expires_at)).await;
+
+        let (clients, rooms) = (Arc::clone(clients), Arc::clone(rooms));
+        tokio::spawn(async move {
+            tokio::time::sleep(ttl).await;
+
+            let expired = Message::MessageExpired { id, seq };
+            if let Err(e) = broadcast_to_room(&clients, &rooms, &room_id, &expired, None).await {
+                eprintln!("Error expiring message: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Delivers a private message to one user: someone in the sender's room, or
+/// for logged-in senders, every connection logged in to the named account.
+/// The sender gets a copy as confirmation.
+async fn handle_direct_message(
+    to: &str,
+    content: &str,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (username, room_id, account, spectator) = {
+        let clients_guard = clients.lock().await;
+        clients_guard.get(client_id).map(|client| {
+            (client.username.clone(), client.room.clone(), client.account.clone(), client.spectator)
+        }).unwrap_or_default()
+    };
+
+    if spectator {
+        send_message(writer, &Message::Error {
+            message: "Spectators cannot send messages".to_string(),
+            kind: ErrorKind::PermissionDenied,
+        }).await?;
+        return Ok(());
+    }
+
+    if room_id.is_none() && account.is_none() {
+        send_message(writer, &Message::Error {
+            message: "Join a room or log in to send direct messages".to_string(),
+            kind: ErrorKind::PermissionDenied,
+        }).await?;
+        return Ok(());
+    }
+
+    let content = match sanitize_chat(content) {
+        Ok(content) => content,
+        Err(message) => {
+            send_message(writer, &Message::Error { message, kind: ErrorKind::InvalidMessage }).await?;
+            return Ok(());
+        }
+    };
+
+    let recipient = {
+        let rooms_guard = rooms.read().await;
+        let clients_guard = clients.lock().await;
+        let to = to.to_lowercase();
+
+        let in_room = room_id.as_ref().and_then(|room_id| {
+            let room = rooms_guard.get(room_id)?;
+            room.clients.iter().chain(&room.spectators)
+                .filter(|id| id.as_str() != client_id)
+                .filter_map(|id| clients_guard.get(id))
+                .find(|c| {
+                    c.held.is_none()
+                        && c.room.as_deref() == Some(room_id.as_str())
+                        && c.username.to_lowercase() == to
+                })
+                .map(|c| (c.username.clone(), vec![Arc::clone(&c.socket)]))
+        });
+
+        // Accounts can be reached anywhere on the server, on every connection
+        // they're logged in on
+        in_room.or_else(|| {
+            account.as_ref()?;
+            let connections: Vec<_> = clients_guard.iter()
+                .filter(|(id, c)| id.as_str() != client_id && c.held.is_none())
+                .filter_map(|(_, c)| Some((c.account.as_ref()?, c)))
+                .filter(|(name, _)| name.to_lowercase() == to)
+                .collect();
+            let name = connections.first()?.0.clone();
+            Some((name, connections.iter().map(|(_, c)| Arc::clone(&c.socket)).collect()))
+        })
+    };
+
+    let Some((recipient, sockets)) = recipient else {
+        let message = if account.is_some() {
+            format!("No user named '{}' is in your room or logged in", to)
+        } else {
+            format!("No user named '{}' is in your room", to)
+        };
+        send_message(writer, &Message::Error { message, kind: ErrorKind::UserNotFound }).await?;
+        return Ok(());
+    };
+
+    let from = account.unwrap_or(username);
+    println!("Direct message from '{}' to '{}'", from, recipient);
+
+    let direct_msg = Message::PrivateMessage { from, to: recipient, content };
+    for socket in &sockets {
+        let _ = send_message(socket, &direct_msg).await;
+    }
+    send_message(writer, &direct_msg).await
+}
+
+/// Forwards a typing notice to the rest of the room, never back to the
+/// sender. Notices arriving faster than `TYPING_RATE_LIMIT` are dropped.
+async fn handle_typing(
+    active: bool,
+    client_id: &str,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (username, author_id, room_id) = {
+        let clients_guard = clients.lock().await;
+        match clients_guard.get(client_id) {
+            Some(client) if !client.spectator => (client.username.clone(), client.author_id(), client.room.clone()),
+            _ => return Ok(()),
+        }
+    };
+
+    let Some(room_id) = room_id else {
+        return Ok(());
+    };
+
+    // Nobody should see "typing" from someone who can't post
+    let can_post = {
+        let rooms_guard = rooms.read().await;
+        rooms_guard.get(&room_id).is_some_and(|room| room.can_post(client_id, &author_id))
+    };
+    if !can_post {
+        return Ok(());
+    }
+
+    let forward = {
+        let mut clients_guard = clients.lock().await;
+        let Some(client) = clients_guard.get_mut(client_id) else {
+            return Ok(());
+        };
+
+        let now = Instant::now();
+        let forward = match client.last_typing {
+            Some(last) if active => now.duration_since(last) >= TYPING_RATE_LIMIT,
+            Some(_) => true,
+            None => active,
+        };
+        if forward {
+            client.last_typing = active.then_some(now);
+        }
+        forward
+    };
+
+    if forward {
+        let typing_msg = Message::UserTyping { username, active };
+        broadcast_to_room(clients, rooms, &room_id, &typing_msg, Some(client_id)).await?;
+    }
+
+    Ok(())
+}
+
+/// Renames a client without leaving the room, so they keep their seat and
+/// any moderator or poster permissions.
+async fn handle_change_nick(
+    requested: &str,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+    accounts: &Accounts,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (old_name, author_id, room_id, account, spectator) = {
+        let clients_guard = clients.lock().await;
+        clients_guard.get(client_id).map(|client| {
+            (client.username.clone(), client.author_id(), client.room.clone(), client.account.clone(), client.spectator)
+        }).unwrap_or_default()
+    };
+
+    let Some(room_id) = room_id else {
+        send_message(writer, &Message::Error {
+            message: "Join a room before changing your nickname".to_string(),
+            kind: ErrorKind::Other,
+        }).await?;
+        return Ok(());
+    };
+
+    if account.is_some() {
+        send_message(writer, &Message::Error {
+            message: "Logged-in users always appear under their account name".to_string(),
+            kind: ErrorKind::PermissionDenied,
+        }).await?;
+        return Ok(());
+    }
+
+    if requested == old_name {
+        return Ok(());
+    }
+
+    let Some(new_name) = resolve_username(requested, client_id, writer, clients, accounts).await? else {
+        return Ok(());
+    };
+
+    // A change of case only collides with the client's own name, which is fine
+    let case_only = new_name.to_lowercase() == old_name.to_lowercase();
+    if !case_only && !check_username(&room_id, &new_name, client_id, writer, clients, rooms).await? {
+        return Ok(());
+    }
+
+    // Check the name again and rename under one lock, so two clients can't
+    // both take the same free name
+    let renamed = {
+        let mut rooms_guard = rooms.write().await;
+        let mut clients_guard = clients.lock().await;
+        let Some(room) = rooms_guard.get_mut(&room_id) else {
+            return Ok(());
+        };
+        let still_here = clients_guard.get(client_id)
+            .is_some_and(|client| client.room.as_ref() == Some(&room_id) && client.username == old_name);
+        if !still_here {
+            return Ok(());
+        }
+        if is_name_in_use(room, &clients_guard, &new_name, client_id) {
+            None
+        } else {
+            for listed in [&mut room.moderators, &mut room.posters].into_iter().filter_map(|names| names.get_mut(&author_id)) {
+                *listed = new_name.clone();
+            }
+            for poll in room.polls.values_mut().filter(|poll| poll.creator_id == author_id) {
+                poll.created_by = new_name.clone();
+            }
+
+            if let Some(client) = clients_guard.get_mut(client_id) {
+                client.username = new_name.clone();
+            }
+            Some(room.name.clone())
+        }
+    };
+
+    let Some(room_name) = renamed else {
+        return send_username_taken(&new_name, writer).await;
+    };
+
+    println!("User '{}' is now known as '{}' in room '{}'", old_name, new_name, room_name);
+
+    // Spectators aren't listed to the room, so only they hear about it
+    let nick_msg = Message::NickChanged { old: old_name, new: new_name };
+    if spectator {
+        send_message(writer, &nick_msg).await
+    } else {
+        broadcast_to_room(clients, rooms, &room_id, &nick_msg, None).await
+    }
+}
+
+/// Updates a client's presence and status line. The change is broadcast to
+/// their room, or just confirmed to them if they aren't in one or are only
+/// spectating it.
+async fn handle_set_presence(
+    presence: Presence,
+    status: Option<&str>,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let status = match sanitize_status(status.unwrap_or_default()) {
+        Ok(status) => status,
+        Err(message) => {
+            send_message(writer, &Message::Error { message, kind: ErrorKind::InvalidMessage }).await?;
+            return Ok(());
+        }
+    };
+
+    let (username, room_id) = {
+        let mut clients_guard = clients.lock().await;
+        let Some(client) = clients_guard.get_mut(client_id) else {
+            return Ok(());
+        };
+        client.presence = presence;
+        client.status = status.clone();
+        (client.username.clone(), client.room.clone().filter(|_| !client.spectator))
+    };
+
+    let presence_msg = Message::PresenceChanged { username, presence, status };
+    match room_id {
+        Some(room_id) => broadcast_to_room(clients, rooms, &room_id, &presence_msg, None).await,
+        None => send_message(writer, &presence_msg).await,
+    }
+}
+
+/// Returns a page of persisted history, for the room the client is in.
+async fn handle_get_history(
+    room_id: &str,
+    before: Option<u64>,
+    limit: usize,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    rooms: &Rooms,
+    message_log: &MessageLog,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !is_room_member(client_id, room_id, rooms).await {
+        send_message(writer, &Message::Error {
+            message: "You can only read the history of a room you're in".to_string(),
+            kind: ErrorKind::PermissionDenied,
+        }).await?;
+        return Ok(());
+    }
+
+    let (log_room_id, limit) = (room_id.to_string(), limit.min(MAX_HISTORY_PAGE));
+    let mut messages = match with_message_log(message_log, move |store| store.before(&log_room_id, before, limit)).await {
+        Ok(messages) => messages,
+        Err(e) => {
+            send_history_service_error(e, writer).await?;
+            return Ok(());
+        }
+    };
+
+    add_reactions(room_id, &mut messages, rooms).await;
+    send_message(writer, &Message::History { room_id: room_id.to_string(), messages }).await
+}
+
+async fn handle_search_history(
+    room_id: &str,
+    query: &str,
+    limit: usize,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    rooms: &Rooms,
+    message_log: &MessageLog,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !is_room_member(client_id, room_id, rooms).await {
+        send_message(writer, &Message::Error {
+            message: "You can only search the history of a room you're in".to_string(),
+            kind: ErrorKind::PermissionDenied,
+        }).await?;
+        return Ok(());
+    }
+
+    let query = match sanitize_search(query) {
+        Ok(query) => query,
+        Err(e) => {
+            send_message(writer, &Message::Error { message: e, kind: ErrorKind::InvalidMessage }).await?;
+            return Ok(());
+        }
+    };
+
+    let (log_room_id, log_query, limit) = (room_id.to_string(), query.clone(), limit.min(MAX_SEARCH_LIMIT));
+    let mut messages = match with_message_log(message_log, move |store| store.search(&log_room_id, &log_query, limit)).await {
+        Ok(messages) => messages,
+        Err(e) => {
+            send_history_service_error(e, writer).await?;
+            return Ok(());
+        }
+    };
+
+    add_reactions(room_id, &mut messages, rooms).await;
+    send_message(writer, &Message::SearchResults { room_id: room_id.to_string(), query, messages }).await
+}
+
+async fn handle_get_thread(
+    room_id: &str,
+    message_id: &str,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    rooms: &Rooms,
+    message_log: &MessageLog,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !is_room_member(client_id, room_id, rooms).await {
+        send_message(writer, &Message::Error {
+            message: "You can only read the history of a room you're in".to_string(),
+            kind: ErrorKind::PermissionDenied,
+        }).await?;
+        return Ok(());
+    }
+
+    let (log_room_id, log_id) = (room_id.to_string(), message_id.to_string());
+    let thread = with_message_log(message_log, move |store| {
+        let Some(root_id) = store.thread_root(&log_room_id, &log_id)? else {
+            return Ok(None);
+        };
+        let messages = store.thread(&log_room_id, &root_id, MAX_HISTORY_PAGE)?;
+        Ok(Some((root_id, messages)))
+    }).await;
+
+    match thread {
+        Ok(Some((root_id, mut messages))) => {
+            add_reactions(room_id, &mut messages, rooms).await;
+            send_message(writer, &Message::Thread { room_id: room_id.to_string(), root_id, messages }).await
+        }
+        Ok(None) => {
+            send_message(writer, &Message::Error {
+                message: "That message doesn't exist in this room".to_string(),
+                kind: ErrorKind::MessageNotFound,
+            }).await
+        }
+        Err(e) => send_history_service_error(e, writer).await,
+    }
+}
+
+async fn handle_edit_message(
+    message_id: &str,
+    content: &str,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+    message_log: &MessageLog,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let content = match sanitize_chat(content) {
+        Ok(content) => content,
+        Err(message) => {
+            send_message(writer, &Message::Error { message, kind: ErrorKind::InvalidMessage }).await?;
+            return Ok(());
+        }
+    };
+
+    let Some((room_id, username, mut message)) =
+        find_changeable_message(message_id, client_id, writer, clients, rooms, message_log).await?
+    else {
+        return Ok(());
+    };
+
+    message.content = content;
+    message.edited_at = Some(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true));
+    {
+        let mut rooms_guard = rooms.write().await;
+        if let Some(room) = rooms_guard.get_mut(&room_id) {
+            for kept in room.kept_messages_mut() {
+                if kept.id == message.id {
+                    *kept = message.clone();
+                } else if let Some(quote) = kept.reply_to.as_mut().filter(|quote| quote.id == message.id) {
+                    quote.snippet = Some(history::snippet(&message.content));
+                }
+            }
+        }
+    }
+
+    let (log_room_id, log_message) = (room_id.clone(), message.clone());
+    if let Err(e) = with_message_log(message_log, move |store| store.update(&log_room_id, &log_message)).await {
+        eprintln!("Message history error: {}", e);
+    }
+
+    println!("User '{}' edited message #{} by '{}'", username, message.seq, message.username);
+    let StoredMessage { id, seq, content, edited_at, .. } = message;
+    let edited = Message::MessageEdited { id, seq, content, edited_at: edited_at.unwrap_or_default(), by: username };
+    broadcast_to_room(clients, rooms, &room_id, &edited, None).await
+}
+
+async fn handle_delete_message(
+    message_id: &str,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+    message_log: &MessageLog,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some((room_id, username, message)) =
+        find_changeable_message(message_id, client_id, writer, clients, rooms, message_log).await?
+    else {
+        return Ok(());
+    };
+
+    {
+        let mut rooms_guard = rooms.write().await;
+        if let Some(room) = rooms_guard.get_mut(&room_id) {
+            room.history.retain(|record| record.message.id != message.id);
+            room.pinned.retain(|pinned| pinned.id != message.id);
+            room.reactions.remove(&message.id);
+            for quote in room.kept_messages_mut().filter_map(|kept| kept.reply_to.as_mut()) {
+                if quote.id == message.id {
+                    quote.snippet = None;
+                }
+            }
+        }
+    }
+
+    let (log_room_id, log_id) = (room_id.clone(), message.id.clone());
+    if let Err(e) = with_message_log(message_log, move |store| store.delete(&log_room_id, &log_id)).await {
+        eprintln!("Message history error: {}", e);
+    }
+
+    println!("User '{}' deleted message #{} by '{}'", username, message.seq, message.username);
+    let deleted = Message::MessageDeleted { id: message.id, seq: message.seq, by: username };
+    broadcast_to_room(clients, rooms, &room_id, &deleted, None).await
+}
+
+async fn handle_react(
+    message_id: &str,
+    emoji: &str,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+    message_log: &MessageLog,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let emoji = match validate_reaction(emoji) {
+        Ok(emoji) => emoji,
+        Err(message) => {
+            send_message(writer, &Message::Error { message, kind: ErrorKind::InvalidMessage }).await?;
+            return Ok(());
+        }
+    };
+
+    let Some((author_id, room_id)) = reacting_member(client_id, writer, clients).await? else {
+        return Ok(());
+    };
+
+    let message = find_requested_message(&room_id, message_id, writer, rooms, message_log).await?;
+    let Some(message) = message else {
+        return Ok(());
+    };
+
+    let tally = {
+        let mut rooms_guard = rooms.write().await;
+        let Some(room) = rooms_guard.get_mut(&room_id) else {
+            return Ok(());
+        };
+        let reactions = room.reactions.entry(message.id.clone())
+            .or_insert_with(|| MessageReactions { seq: message.seq, users: BTreeMap::new() });
+
+        if !reactions.users.contains_key(&emoji) && reactions.users.len() >= MAX_REACTIONS_PER_MESSAGE {
+            None
+        } else if reactions.users.entry(emoji).or_default().insert(author_id) {
+            Some(reactions.tally())
+        } else {
+            // Already reacted with this emoji
+            return Ok(());
+        }
+    };
+
+    let Some(reactions) = tally else {
+        send_message(writer, &Message::Error {
+            message: format!("A message can have at most {} different reactions", MAX_REACTIONS_PER_MESSAGE),
+            kind: ErrorKind::InvalidMessage,
+        }).await?;
+        return Ok(());
+    };
+
+    let updated = Message::ReactionsUpdated { message_id: message.id, seq: message.seq, reactions };
+    broadcast_to_room(clients, rooms, &room_id, &updated, None).await
+}
+
+async fn handle_unreact(
+    message_id: &str,
+    emoji: &str,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some((author_id, room_id)) = reacting_member(client_id, writer, clients).await? else {
+        return Ok(());
+    };
+
+    let (seq, reactions) = {
+        let mut rooms_guard = rooms.write().await;
+        let Some(room) = rooms_guard.get_mut(&room_id) else {
+            return Ok(());
+        };
+        let Some(reactions) = room.reactions.get_mut(message_id) else {
+            return Ok(());
+        };
+        let emoji = emoji.trim();
+        let Some(users) = reactions.users.get_mut(emoji) else {
+            return Ok(());
+        };
+        if !users.remove(&author_id) {
+            return Ok(());
+        }
+        if users.is_empty() {
+            reactions.users.remove(emoji);
+        }
+
+        let updated = (reactions.seq, reactions.tally());
+        if reactions.users.is_empty() {
+            room.reactions.remove(message_id);
+        }
+        updated
+    };
+
+    let updated = Message::ReactionsUpdated { message_id: message_id.to_string(), seq, reactions };
+    broadcast_to_room(clients, rooms, &room_id, &updated, None).await
+}
+
+/// The author ID and room of a client who may react to messages, which is
+/// anyone seated in a room. Tells the client why not and returns `None`
+/// otherwise.
+async fn reacting_member(
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+) -> Result<Option<(String, String)>, Box<dyn std::error::Error>> {
+    let (author_id, room_id, spectator) = {
+        let clients_guard = clients.lock().await;
+        clients_guard.get(client_id)
+            .map(|client| (client.author_id(), client.room.clone(), client.spectator))
+            .unwrap_or_default()
+    };
+
+    let message = match room_id {
+        Some(_) if spectator => "Spectators cannot react to messages",
+        Some(room_id) => return Ok(Some((author_id, room_id))),
+        None => "Join a room before reacting to messages",
+    };
+    send_message(writer, &Message::Error { message: message.to_string(), kind: ErrorKind::PermissionDenied }).await?;
+    Ok(None)
+}
+
+async fn handle_pin_message(
+    message_id: &str,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+    message_log: &MessageLog,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some((username, room_id)) = pinning_moderator(client_id, writer, clients, rooms).await? else {
+        return Ok(());
+    };
+
+    let message = find_requested_message(&room_id, message_id, writer, rooms, message_log).await?;
+    let Some(mut message) = message else {
+        return Ok(());
+    };
+
+    let room_name = {
+        let mut rooms_guard = rooms.write().await;
+        let Some(room) = rooms_guard.get_mut(&room_id) else {
+            return Ok(());
+        };
+        if room.pinned.iter().any(|pinned| pinned.id == message.id) {
+            // Already pinned
+            return Ok(());
+        }
+        if room.pinned.len() >= MAX_PINNED_MESSAGES {
+            None
+        } else {
+            room.pinned.push(message.clone());
+            message.reactions = room.reaction_tally(&message.id);
+            Some(room.name.clone())
+        }
+    };
+
+    let Some(room_name) = room_name else {
+        send_message(writer, &Message::Error {
+            message: format!("A room can have at most {} pinned messages, unpin one first", MAX_PINNED_MESSAGES),
+            kind: ErrorKind::InvalidMessage,
+        }).await?;
+        return Ok(());
+    };
+
+    println!("User '{}' pinned message #{} in room '{}'", username, message.seq, room_name);
+    let pinned = Message::MessagePinned { message, by: username };
+    broadcast_to_room(clients, rooms, &room_id, &pinned, None).await
+}
+
+async fn handle_unpin_message(
+    message_id: &str,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some((username, room_id)) = pinning_moderator(client_id, writer, clients, rooms).await? else {
+        return Ok(());
+    };
+
+    let (message, room_name) = {
+        let mut rooms_guard = rooms.write().await;
+        let Some(room) = rooms_guard.get_mut(&room_id) else {
+            return Ok(());
+        };
+        let Some(index) = room.pinned.iter().position(|pinned| pinned.id == message_id) else {
+            return Ok(());
+        };
+        (room.pinned.remove(index), room.name.clone())
+    };
+
+    println!("User '{}' unpinned message #{} in room '{}'", username, message.seq, room_name);
+    let unpinned = Message::MessageUnpinned { id: message.id, seq: message.seq, by: username };
+    broadcast_to_room(clients, rooms, &room_id, &unpinned, None).await
+}
+
+/// The username and room of a client who may pin messages: the owner or a
+/// moderator of their room. Tells the client why not and returns `None`
+/// otherwise.
+async fn pinning_moderator(
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<Option<(String, String)>, Box<dyn std::error::Error>> {
+    let (username, author_id, room_id) = {
+        let clients_guard = clients.lock().await;
+        clients_guard.get(client_id)
+            .map(|client| (client.username.clone(), client.author_id(), client.room.clone()))
+            .unwrap_or_default()
+    };
+
+    let Some(room_id) = room_id else {
+        send_message(writer, &Message::Error {
+            message: "Join a room before pinning messages".to_string(),
+            kind: ErrorKind::PermissionDenied,
+        }).await?;
+        return Ok(None);
+    };
+
+    let is_moderator = {
+        let rooms_guard = rooms.read().await;
+        rooms_guard.get(&room_id).is_some_and(|room| room.is_moderator(client_id, &author_id))
+    };
+    if !is_moderator {
+        send_message(writer, &Message::Error {
+            message: "Only moderators can pin messages".to_string(),
+            kind: ErrorKind::PermissionDenied,
+        }).await?;
+        return Ok(None);
+    }
+
+    Ok(Some((username, room_id)))
+}
+
+async fn handle_create_poll(
+    question: &str,
+    options: &[String],
+    duration_secs: Option<u64>,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (username, author_id, room_id, spectator) = {
+        let clients_guard = clients.lock().await;
+        clients_guard.get(client_id)
+            .map(|client| (client.username.clone(), client.author_id(), client.room.clone(), client.spectator))
+            .unwrap_or_default()
+    };
+
+    let can_post = match &room_id {
+        Some(room_id) => {
+            let rooms_guard = rooms.read().await;
+            rooms_guard.get(room_id).is_some_and(|room| room.can_post(client_id, &author_id))
+        }
+        None => false,
+    };
+
+    let room_id = match room_id {
+        Some(room_id) if !spectator && can_post => room_id,
+        _ => {
+            let message = match room_id {
+                None => "Join a room before starting a poll",
+                Some(_) if spectator => "Spectators cannot start polls",
+                Some(_) => "Only moderators and approved posters can start polls in this room",
+            };
+            send_message(writer, &Message::Error { message: message.to_string(), kind: ErrorKind::PermissionDenied }).await?;
+            return Ok(());
+        }
+    };
+
+    let (question, options) = match sanitize_poll(question, options) {
+        Ok(poll) => poll,
+        Err(message) => {
+            send_message(writer, &Message::Error { message, kind: ErrorKind::InvalidMessage }).await?;
+            return Ok(());
+        }
+    };
+
+    let duration = match duration_secs {
+        Some(secs @ 1..=MAX_POLL_DURATION_SECS) => Some(Duration::from_secs(secs)),
+        Some(_) => {
+            send_message(writer, &Message::Error {
+                message: format!("Polls can stay open for between 1 and {} seconds", MAX_POLL_DURATION_SECS),
+                kind: ErrorKind::InvalidMessage,
+            }).await?;
+            return Ok(());
+        }
+        None => None,
+    };
+
+    let started = {
+        let mut rooms_guard = rooms.write().await;
+        let Some(room) = rooms_guard.get_mut(&room_id) else {
+            return Ok(());
+        };
+        if room.polls.len() >= MAX_OPEN_POLLS {
+            None
+        } else {
+            room.last_poll += 1;
+            let poll = Poll {
+                question,
+                options,
+                created_by: username.clone(),
+                creator_id: author_id,
+                closes_at: duration.map(|duration| {
+                    (Utc::now() + duration).to_rfc3339_opts(SecondsFormat::Millis, true)
+                }),
+                votes: HashMap::new(),
+            };
+            let summary = poll.summary(room.last_poll);
+            room.polls.insert(room.last_poll, poll);
+            Some((summary, room.name.clone()))
+        }
+    };
+
+    let Some((poll, room_name)) = started else {
+        send_message(writer, &Message::Error {
+            message: format!("A room can have at most {} open polls, close one first", MAX_OPEN_POLLS),
+            kind: ErrorKind::InvalidMessage,
+        }).await?;
+        return Ok(());
+    };
+
+    println!("User '{}' started poll #{} in room '{}'", username, poll.id, room_name);
+    let poll_id = poll.id;
+    broadcast_to_room(clients, rooms, &room_id, &Message::PollStarted { poll }, None).await?;
+
+    if let Some(duration) = duration {
+        let (clients, rooms) = (Arc::clone(clients), Arc::clone(rooms));
+        tokio::spawn(async move {
+            tokio::time::sleep(duration).await;
+
+            if let Err(e) = close_poll(&room_id, poll_id, None, &clients, &rooms).await {
+                eprintln!("Error closing poll: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn handle_vote(
+    poll_id: u64,
+    option: usize,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (author_id, room_id, spectator) = {
+        let clients_guard = clients.lock().await;
+        clients_guard.get(client_id)
+            .map(|client| (client.author_id(), client.room.clone(), client.spectator))
+            .unwrap_or_default()
+    };
+
+    let room_id = match room_id {
+        Some(room_id) if !spectator => room_id,
+        _ => {
+            let message = if spectator { "Spectators cannot vote" } else { "Join a room before voting" };
+            send_message(writer, &Message::Error { message: message.to_string(), kind: ErrorKind::PermissionDenied }).await?;
+            return Ok(());
+        }
+    };
+
+    let outcome = {
+        let mut rooms_guard = rooms.write().await;
+        let Some(room) = rooms_guard.get_mut(&room_id) else {
+            return Ok(());
+        };
+        match room.polls.get_mut(&poll_id) {
+            None => Err(Message::Error {
+                message: format!("There's no open poll #{} in this room", poll_id),
+                kind: ErrorKind::PollNotFound,
+            }),
+            Some(poll) if option >= poll.options.len() => Err(Message::Error {
+                message: format!("Poll #{} only has {} options", poll_id, poll.options.len()),
+                kind: ErrorKind::InvalidMessage,
+            }),
+            Some(poll) => {
+                if poll.votes.insert(author_id, option) == Some(option) {
+                    // Same vote as before
+                    return Ok(());
+                }
+                Ok(poll.summary(poll_id))
+            }
+        }
+    };
+
+    match outcome {
+        Ok(poll) => broadcast_to_room(clients, rooms, &room_id, &Message::PollUpdated { poll }, None).await,
+        Err(error) => send_message(writer, &error).await,
+    }
+}
+
+async fn handle_close_poll(
+    poll_id: u64,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (username, author_id, room_id) = {
+        let clients_guard = clients.lock().await;
+        clients_guard.get(client_id)
+            .map(|client| (client.username.clone(), client.author_id(), client.room.clone()))
+            .unwrap_or_default()
+    };
+
+    let Some(room_id) = room_id else {
+        send_message(writer, &Message::Error {
+            message: "Join a room before closing polls".to_string(),
+            kind: ErrorKind::PermissionDenied,
+        }).await?;
+        return Ok(());
+    };
+
+    let allowed = {
+        let rooms_guard = rooms.read().await;
+        rooms_guard.get(&room_id).and_then(|room| {
+            let poll = room.polls.get(&poll_id)?;
+            Some(poll.creator_id == author_id || room.is_moderator(client_id, &author_id))
+        })
+    };
+
+    match allowed {
+        Some(true) => close_poll(&room_id, poll_id, Some(username), clients, rooms).await,
+        Some(false) => {
+            send_message(writer, &Message::Error {
+                message: "Only the poll's creator or a moderator can close it".to_string(),
+                kind: ErrorKind::PermissionDenied,
+            }).await
+        }
+        None => {
+            send_message(writer, &Message::Error {
+                message: format!("There's no open poll #{} in this room", poll_id),
+                kind: ErrorKind::PollNotFound,
+            }).await
+        }
+    }
+}
+
+/// Closes a poll and announces its final result. Does nothing if it's
+/// already closed.
+async fn close_poll(
+    room_id: &str,
+    poll_id: u64,
+    by: Option<String>,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let closed = {
+        let mut rooms_guard = rooms.write().await;
+        rooms_guard.get_mut(room_id).and_then(|room| {
+            let poll = room.polls.remove(&poll_id)?;
+            Some((poll.summary(poll_id), room.name.clone()))
+        })
+    };
+
+    let Some((poll, room_name)) = closed else {
+        return Ok(());
+    };
+
+    match &by {
+        Some(username) => println!("User '{}' closed poll #{} in room '{}'", username, poll_id, room_name),
+        None => println!("Poll #{} in room '{}' reached its deadline", poll_id, room_name),
+    }
+    broadcast_to_room(clients, rooms, room_id, &Message::PollClosed { poll, by }, None).await
+}
+
+async fn handle_schedule_message(
+    content: &str,
+    send_at: &str,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+    message_log: &MessageLog,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (username, author_id, room_id, spectator) = {
+        let clients_guard = clients.lock().await;
+        clients_guard.get(client_id)
+            .map(|client| (client.username.clone(), client.author_id(), client.room.clone(), client.spectator))
+            .unwrap_or_default()
+    };
+
+    let can_post = match &room_id {
+        Some(room_id) => {
+            let rooms_guard = rooms.read().await;
+            rooms_guard.get(room_id).is_some_and(|room| room.can_post(client_id, &author_id))
+        }
+        None => false,
+    };
+
+    let room_id = match room_id {
+        Some(room_id) if !spectator && can_post => room_id,
+        _ => {
+            let message = match room_id {
+                None => "Join a room before scheduling messages",
+                Some(_) if spectator => "Spectators cannot send messages",
+                Some(_) => "Only moderators and approved posters can post in this room",
+            };
+            send_message(writer, &Message::Error { message: message.to_string(), kind: ErrorKind::PermissionDenied }).await?;
+            return Ok(());
+        }
+    };
+
+    let content = match sanitize_chat(content) {
+        Ok(content) => content,
+        Err(message) => {
+            send_message(writer, &Message::Error { message, kind: ErrorKind::InvalidMessage }).await?;
+            return Ok(());
+        }
+    };
+
+    let now = Utc::now();
+    let send_at = match DateTime::parse_from_rfc3339(send_at) {
+        Ok(send_at) => send_at.with_timezone(&Utc),
+        Err(_) => {
+            send_message(writer, &Message::Error {
+                message: "Scheduled time must be an RFC 3339 timestamp".to_string(),
+                kind: ErrorKind::InvalidMessage,
+            }).await?;
+            return Ok(());
+        }
+    };
+    if send_at <= now || (send_at - now).num_seconds() > MAX_SCHEDULE_AHEAD_SECS {
+        send_message(writer, &Message::Error {
+            message: format!(
+                "Messages can be scheduled up to {} days ahead, and not in the past",
+                MAX_SCHEDULE_AHEAD_SECS / (24 * 60 * 60)
+            ),
+            kind: ErrorKind::InvalidMessage,
+        }).await?;
+        return Ok(());
+    }
+
+    let scheduled = {
+        let mut rooms_guard = rooms.write().await;
+        let Some(room) = rooms_guard.get_mut(&room_id) else {
+            return Ok(());
+        };
+        let pending = room.scheduled.values().filter(|scheduled| scheduled.author_id == author_id).count();
+        if pending >= MAX_SCHEDULED_PER_USER {
+            None
+        } else {
+            room.last_scheduled += 1;
+            let scheduled = ScheduledMessage { author_id, content, send_at };
+            let summary = scheduled.summary(room.last_scheduled);
+            room.scheduled.insert(room.last_scheduled, scheduled);
+            Some((summary, room.name.clone()))
+        }
+    };
+
+    let Some((message, room_name)) = scheduled else {
+        send_message(writer, &Message::Error {
+            message: format!("You can have at most {} scheduled messages in a room", MAX_SCHEDULED_PER_USER),
+            kind: ErrorKind::InvalidMessage,
+        }).await?;
+        return Ok(());
+    };
+
+    println!("User '{}' scheduled message #{} in room '{}' for {}", username, message.id, room_name, message.send_at);
+    let id = message.id;
+    send_message(writer, &Message::MessageScheduled { message }).await?;
+
+    // Tokio's timer wheel keeps the wait cheap; a cancelled message is simply
+    // gone from the room when it wakes
+    let delay = (send_at - now).to_std().unwrap_or_default();
+    let (clients, rooms, message_log) = (Arc::clone(clients), Arc::clone(rooms), Arc::clone(message_log));
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        send_scheduled_message(&room_id, id, &clients, &rooms, &message_log).await;
+    });
+
+    Ok(())
+}
+
+/// Posts a scheduled message to its room like any other chat message, as
+/// long as it hasn't been cancelled and its author is still in the room and
+/// allowed to post. Otherwise it's dropped.
+async fn send_scheduled_message(
+    room_id: &str,
+    id: u64,
+    clients: &Clients,
+    rooms: &Rooms,
+    message_log: &MessageLog,
+) {
+    let (scheduled, author, room_name) = {
+        let mut rooms_guard = rooms.write().await;
+        let Some(room) = rooms_guard.get_mut(room_id) else {
+            return;
+        };
+        let Some(scheduled) = room.scheduled.remove(&id) else {
+            return;
+        };
+
+        // The author may have resumed on a new connection or changed their name since
+        let clients_guard = clients.lock().await;
+        let author = room.clients.iter()
+            .filter_map(|seated| clients_guard.get(seated).map(|client| (seated, client)))
+            .find(|(_, client)| client.author_id() == scheduled.author_id)
+            .filter(|(seated, _)| room.can_post(seated, &scheduled.author_id))
+            .map(|(seated, client)| (seated.clone(), client.username.clone()));
+        (scheduled, author, room.name.clone())
+    };
+
+    let Some((client_id, username)) = author else {
+        println!(
+            "Dropped scheduled message #{} in room '{}': its author has left or can no longer post",
+            id, room_name
+        );
+        return;
+    };
+
+    let ScheduledMessage { author_id, content, .. } = scheduled;
+    let Some(stored) = stamp_message(room_id, username, Some(author_id), content, None, false, rooms).await else {
+        return;
+    };
+    println!("Sent scheduled message #{} by '{}' as message #{}", id, stored.username, stored.seq);
+    store_message(room_id, &stored, message_log).await;
+    broadcast_chat(&client_id, clients, rooms, room_id, stored, None).await;
+}
+
+async fn handle_get_scheduled_messages(
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (author_id, room_id) = {
+        let clients_guard = clients.lock().await;
+        clients_guard.get(client_id)
+            .map(|client| (client.author_id(), client.room.clone()))
+            .unwrap_or_default()
+    };
+
+    let Some(room_id) = room_id else {
+        send_message(writer, &Message::Error {
+            message: "Join a room before listing scheduled messages".to_string(),
+            kind: ErrorKind::PermissionDenied,
+        }).await?;
+        return Ok(());
+    };
+
+    let mut messages: Vec<ScheduledSummary> = {
+        let rooms_guard = rooms.read().await;
+        rooms_guard.get(&room_id)
+            .map(|room| {
+                room.scheduled.iter()
+                    .filter(|(_, scheduled)| scheduled.author_id == author_id)
+                    .map(|(&id, scheduled)| scheduled.summary(id))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    // RFC 3339 in UTC sorts by time
+    messages.sort_by(|a, b| a.send_at.cmp(&b.send_at));
+
+    send_message(writer, &Message::ScheduledMessages { room_id, messages }).await
+}
+
+async fn handle_cancel_scheduled_message(
+    id: u64,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (username, author_id, room_id) = {
+        let clients_guard = clients.lock().await;
+        clients_guard.get(client_id)
+            .map(|client| (client.username.clone(), client.author_id(), client.room.clone()))
+            .unwrap_or_default()
+    };
+
+    let cancelled = match &room_id {
+        Some(room_id) => {
+            let mut rooms_guard = rooms.write().await;
+            rooms_guard.get_mut(room_id)
+                .filter(|room| room.scheduled.get(&id).is_some_and(|scheduled| scheduled.author_id == author_id))
+                .and_then(|room| room.scheduled.remove(&id).map(|_| room.name.clone()))
+        }
+        None => None,
+    };
+
+    // Other people's scheduled messages are private, so they're reported as missing
+    let Some(room_name) = cancelled else {
+        send_message(writer, &Message::Error {
+            message: format!("You have no scheduled message #{} in this room", id),
+            kind: ErrorKind::MessageNotFound,
+        }).await?;
+        return Ok(());
+    };
+
+    println!("User '{}' cancelled scheduled message #{} in room '{}'", username, id, room_name);
+    send_message(writer, &Message::ScheduledMessageCancelled { id }).await
+}
+
+/// Finds a message in the client's current room that they're allowed to
+/// edit or delete: their own, or any message in a room they moderate.
+/// Returns the room ID, the client's username and the message, or tells the
+/// client why not and returns `None`.
+async fn find_changeable_message(
+    message_id: &str,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+    message_log: &MessageLog,
+) -> Result<Option<(String, String, StoredMessage)>, Box<dyn std::error::Error>> {
+    let (username, author_id, room_id, spectator) = {
+        let clients_guard = clients.lock().await;
+        clients_guard.get(client_id)
+            .map(|client| (client.username.clone(), client.author_id(), client.room.clone(), client.spectator))
+            .unwrap_or_default()
+    };
+
+    let room_id = match room_id {
+        Some(room_id) if !spectator => room_id,
+        _ => {
+            let message = if spectator {
+                "Spectators cannot change messages"
+            } else {
+                "Join a room before changing messages"
+            };
+            send_message(writer, &Message::Error { message: message.to_string(), kind: ErrorKind::PermissionDenied }).await?;
+            return Ok(None);
+        }
+    };
+
+    let is_moderator = {
+        let rooms_guard = rooms.read().await;
+        rooms_guard.get(&room_id).is_some_and(|room| room.is_moderator(client_id, &author_id))
+    };
+
+    let message = find_requested_message(&room_id, message_id, writer, rooms, message_log).await?;
+    let Some(message) = message else {
+        return Ok(None);
+    };
+
+    // Names can be given up and taken by someone else, so go by who wrote it
+    if message.author_id.as_ref() != Some(&author_id) && !is_moderator {
+        send_message(writer, &Message::Error {
+            message: "You can only change your own messages".to_string(),
+            kind: ErrorKind::PermissionDenied,
+        }).await?;
+        return Ok(None);
+    }
+
+    Ok(Some((room_id, username, message)))
+}
+
+/// Looks up a message a client asked about in a room, telling them if it
+/// doesn't exist or the history can't be read.
+async fn find_requested_message(
+    room_id: &str,
+    message_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    rooms: &Rooms,
+    message_log: &MessageLog,
+) -> Result<Option<StoredMessage>, Box<dyn std::error::Error>> {
+    let message = match find_room_message(room_id, message_id, rooms, message_log).await {
+        Ok(message) => message,
+        Err(e) => {
+            send_history_service_error(e, writer).await?;
+            return Ok(None);
+        }
+    };
+
+    if message.is_none() {
+        send_message(writer, &Message::Error {
+            message: "That message doesn't exist in this room".to_string(),
+            kind: ErrorKind::MessageNotFound,
+        }).await?;
+    }
+    Ok(message)
+}
+
+/// Looks up a message by ID in a room. Recent messages are still in memory;
+/// older ones come from the store.
+async fn find_room_message(
+    room_id: &str,
+    message_id: &str,
+    rooms: &Rooms,
+    message_log: &MessageLog,
+) -> Result<Option<StoredMessage>, String> {
+    {
+        let rooms_guard = rooms.read().await;
+        let recent = rooms_guard.get(room_id)
+            .and_then(|room| room.history.iter().find(|record| record.message.id == message_id));
+        if let Some(record) = recent {
+            return Ok(Some(record.message.clone()));
+        }
+    }
+
+    let (log_room_id, log_id) = (room_id.to_string(), message_id.to_string());
+    with_message_log(message_log, move |store| store.get(&log_room_id, &log_id)).await
+}
+
+async fn handle_get_room_info(
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let room_id = {
+        let clients_guard = clients.lock().await;
+        clients_guard.get(client_id).and_then(|c| c.room.clone())
+    };
+
+    let Some(room_id) = room_id else {
+        return Ok(());
+    };
+
+    let rooms_guard = rooms.read().await;
+    let Some(room) = rooms_guard.get(&room_id) else {
+        return Ok(());
+    };
+
+    let (users, spectators) = {
+        let clients_guard = clients.lock().await;
+        // Skip stale entries for clients that have since moved to another room
+        let in_room = |id: &String| clients_guard.get(id).filter(|c| c.room.as_deref() == Some(room_id.as_str()));
+        let users = room.clients.iter()
+            .filter_map(in_room)
+            .map(|c| RoomUser {
+                username: c.username.clone(),
+                presence: c.presence,
+                status: c.status.clone(),
+            })
+            .collect();
+        let spectators = room.spectators.iter()
+            .filter_map(in_room)
+            .map(|c| c.username.clone())
+            .collect();
+        (users, spectators)
+    };
+
+    send_message(writer, &Message::RoomInfo {
+        room_name: room.name.clone(),
+        users,
+        current_count: room.clients.len(),
+        max_users: room.max_users,
+        spectators,
+        max_spectators: room.max_spectators,
+        announcement_only: room.announcement_only,
+        moderators: sorted(room.moderators.values()),
+        posters: sorted(room.posters.values()),
+        require_login: room.require_login,
+        pinned: room.pinned_messages(),
+    }).await?;
+
+    Ok(())
+}
+
+async fn handle_set_max_spectators(
+    max_spectators: usize,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let room_id = {
+        let clients_guard = clients.lock().await;
+        clients_guard.get(client_id).and_then(|c| c.room.clone())
+    };
+
+    let Some(room_id) = room_id else {
+        return Ok(());
+    };
+
+    // Existing spectators stay; the new cap applies to later joins
+    let updated = {
+        let mut rooms_guard = rooms.write().await;
+        match rooms_guard.get_mut(&room_id) {
+            Some(room) if room.owner == client_id => {
+                room.max_spectators = max_spectators;
+                Some(room.name.clone())
+            }
+            _ => None,
+        }
+    };
+
+    let Some(room_name) = updated else {
+        send_message(writer, &Message::Error {
+            message: "Only the room owner can change the spectator limit".to_string(),
+            kind: ErrorKind::PermissionDenied,
+        }).await?;
+        return Ok(());
+    };
+
+    println!("Room '{}' now allows {} spectators", room_name, max_spectators);
+
+    send_message(writer, &Message::MaxSpectatorsSet { max_spectators }).await?;
+
+    Ok(())
+}
+
+enum PermissionChange<'a> {
+    AnnouncementOnly(bool),
+    Moderator(&'a str, bool),
+    Poster(&'a str, bool),
+}
+
+async fn handle_permission_change(
+    change: PermissionChange<'_>,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (username, author_id, room_id) = {
+        let clients_guard = clients.lock().await;
+        clients_guard.get(client_id).map(|client| {
+            (client.username.clone(), client.author_id(), client.room.clone())
+        }).unwrap_or_default()
+    };
+
+    let Some(room_id) = room_id else {
+        return Ok(());
+    };
+
+    let result = {
+        let mut rooms_guard = rooms.write().await;
+        let Some(room) = rooms_guard.get_mut(&room_id) else {
+            return Ok(());
+        };
+
+        // Only the owner may appoint moderators; moderators manage everything else
+        let allowed = match change {
+            PermissionChange::Moderator(..) => room.owner == client_id,
+            _ => room.is_moderator(client_id, &author_id),
+        };
+
+        if allowed {
+            let clients_guard = clients.lock().await;
+            let changed = match change {
+                PermissionChange::AnnouncementOnly(enabled) => {
+                    room.announcement_only = enabled;
+                    Ok(())
+                }
+                PermissionChange::Moderator(name, true) => grant(&mut room.moderators, &room.clients, &clients_guard, name),
+                PermissionChange::Moderator(name, false) => {
+                    revoke(&mut room.moderators, name);
+                    Ok(())
+                }
+                PermissionChange::Poster(name, true) => grant(&mut room.posters, &room.clients, &clients_guard, name),
+                PermissionChange::Poster(name, false) => {
+                    revoke(&mut room.posters, name);
+                    Ok(())
+                }
+            };
+            changed.map(|()| (room.name.clone(), Message::PermissionsUpdated {
+                announcement_only: room.announcement_only,
+                moderators: sorted(room.moderators.values()),
+                posters: sorted(room.posters.values()),
+            }))
+        } else {
+            Err((match change {
+                PermissionChange::Moderator(..) => "Only the room owner can appoint moderators",
+                _ => "Only the room owner and moderators can change posting permissions",
+            }.to_string(), ErrorKind::PermissionDenied))
+        }
+    };
+
+    match result {
+        Ok((room_name, update)) => {
+            println!("User '{}' changed posting permissions in room '{}'", username, room_name);
+            broadcast_to_room(clients, rooms, &room_id, &update, None).await?;
+        }
+        Err((message, kind)) => {
+            send_message(writer, &Message::Error { message, kind }).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Lists the room member called `name` under their author ID. Rights can only
+/// go to someone who's there, so they can't be claimed by taking the name.
+fn grant(
+    listed: &mut HashMap<String, String>,
+    members: &[String],
+    clients: &HashMap<String, Client>,
+    name: &str,
+) -> Result<(), (String, ErrorKind)> {
+    let member = members.iter()
+        .filter_map(|member_id| clients.get(member_id))
+        .find(|client| client.username.to_lowercase() == name.to_lowercase())
+        .ok_or_else(|| (format!("'{}' isn't in this room", name), ErrorKind::UserNotFound))?;
+    listed.insert(member.author_id(), member.username.clone());
+    Ok(())
+}
+
+/// Removes whoever is listed under `name`, whether or not they're still here.
+fn revoke(listed: &mut HashMap<String, String>, name: &str) {
+    listed.retain(|_, listed_name| listed_name.to_lowercase() != name.to_lowercase());
+}
+
+/// Validates `username` and checks it is free in the room, telling the client
+/// why not if it can't be used. Also enforces the room's login requirement.
+async fn check_username(
+    room_id: &str,
+    username: &str,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    if let Err(message) = USERNAME_RULES.validate(username) {
+        send_message(writer, &Message::Error {
+            message,
+            kind: ErrorKind::InvalidUsername,
+        }).await?;
+        return Ok(false);
+    }
+
+    let require_login = {
+        let rooms_guard = rooms.read().await;
+        rooms_guard.get(room_id).is_some_and(|room| room.require_login)
+    };
+
+    if require_login && !is_logged_in(client_id, clients).await {
+        send_message(writer, &Message::Error {
+            message: "This room requires you to log in to a registered account".to_string(),
+            kind: ErrorKind::AuthenticationRequired,
+        }).await?;
+        return Ok(false);
+    }
+
+    if is_username_taken(room_id, username, client_id, clients, rooms).await {
+        send_username_taken(username, writer).await?;
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+async fn send_username_taken(
+    username: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    send_message(writer, &Message::Error {
+        message: format!("Username '{}' is already taken in this room", username),
+        kind: ErrorKind::UsernameTaken,
+    }).await
+}
+
+async fn is_username_taken(
+    room_id: &str,
+    username: &str,
+    client_id: &str,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> bool {
+    let rooms_guard = rooms.read().await;
+    let Some(room) = rooms_guard.get(room_id) else {
+        return false;
+    };
+
+    let clients_guard = clients.lock().await;
+    is_name_in_use(room, &clients_guard, username, client_id)
+}
+
+/// Usernames must be unique across a room's participants, spectators and
+/// waitlist, so every name in `UserMessage` maps to one person. Names are
+/// compared by their skeleton, which ignores case and look-alike letters from
+/// other scripts. The client asking is left out, so it can come back under
+/// its own name.
+fn is_name_in_use(room: &Room, clients: &HashMap<String, Client>, username: &str, client_id: &str) -> bool {
+    let username = skeleton(username);
+    room.clients.iter()
+        .chain(&room.spectators)
+        .chain(&room.waitlist)
+        .filter(|id| *id != client_id)
+        .filter_map(|id| clients.get(id))
+        .any(|client| skeleton(&client.username) == username)
+}
+
+// ============================================================================
+// Account Handling
+// ============================================================================
+
+async fn handle_register(
+    username: &str,
+    password: &str,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    accounts: &Accounts,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let rejection = if let Err(message) = USERNAME_RULES.validate(username) {
+        Some(message)
+    } else if !(MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&password.chars().count()) {
+        Some(format!(
+            "Password must be between {} and {} characters",
+            MIN_PASSWORD_LEN, MAX_PASSWORD_LEN
+        ))
+    } else if is_in_room(client_id, clients).await {
+        Some("Register before joining a room".to_string())
+    } else {
+        None
+    };
+
+    if let Some(message) = rejection {
+        send_message(writer, &Message::Error { message, kind: ErrorKind::RegistrationFailed }).await?;
+        return Ok(());
+    }
+
+    let (name, pass) = (username.to_string(), password.to_string());
+    let registered = match with_accounts(accounts, move |store| store.register(&name, &pass)).await {
+        Ok(registered) => registered,
+        Err(e) => return send_account_service_error(e, writer).await,
+    };
+
+    if !registered {
+        send_message(writer, &Message::Error {
+            message: format!("Username '{}' is already registered", username),
+            kind: ErrorKind::RegistrationFailed,
+        }).await?;
+        return Ok(());
+    }
+
+    println!("Account '{}' registered", username);
+    log_in(username, client_id, writer, clients).await
+}
+
+async fn handle_login(
+    username: &str,
+    password: &str,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    accounts: &Accounts,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if is_in_room(client_id, clients).await {
+        send_message(writer, &Message::Error {
+            message: "Log in before joining a room".to_string(),
+            kind: ErrorKind::AuthenticationFailed,
+        }).await?;
+        return Ok(());
+    }
+
+    let (name, pass) = (username.to_string(), password.to_string());
+    let account = match with_accounts(accounts, move |store| store.verify(&name, &pass)).await {
+        Ok(account) => account,
+        Err(e) => return send_account_service_error(e, writer).await,
+    };
+
+    // Don't reveal whether the username or the password was wrong
+    let Some(account) = account else {
+        let addr = peer_addr(client_id, clients).await;
+        println!("Failed login attempt for account '{}' from {}", username, addr);
+        send_message(writer, &Message::Error {
+            message: "Invalid username or password".to_string(),
+            kind: ErrorKind::AuthenticationFailed,
+        }).await?;
+        return Ok(());
+    };
+
+    log_in(&account, client_id, writer, clients).await
+}
+
+async fn log_in(
+    account: &str,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+) -> Result<(), Box<dyn std::error::Error>> {
+    {
+        let mut clients_guard = clients.lock().await;
+        if let Some(client) = clients_guard.get_mut(client_id) {
+            client.account = Some(account.to_string());
+        }
+    }
+
+    println!("Client {} logged in as '{}'", client_id, account);
+
+    send_message(writer, &Message::LoggedIn { username: account.to_string() }).await
+}
+
+/// Works out which name a joining client appears under. Logged-in clients
+/// always use their account name; guests may not take a registered one.
+async fn resolve_username(
+    requested: &str,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    accounts: &Accounts,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let account = {
+        let clients_guard = clients.lock().await;
+        clients_guard.get(client_id).and_then(|c| c.account.clone())
+    };
+
+    if let Some(account) = account {
+        return Ok(Some(account));
+    }
+
+    let name = requested.to_string();
+    let registered = match with_accounts(accounts, move |store| store.exists(&name)).await {
+        Ok(registered) => registered,
+        Err(e) => {
+            send_account_service_error(e, writer).await?;
+            return Ok(None);
+        }
+    };
+
+    if registered {
+        send_message(writer, &Message::Error {
+            message: format!("Username '{}' belongs to a registered account. Log in to use it", requested),
+            kind: ErrorKind::UsernameTaken,
+        }).await?;
+        return Ok(None);
+    }
+
+    Ok(Some(requested.to_string()))
+}
+
+async fn is_logged_in(client_id: &str, clients: &Clients) -> bool {
+    let clients_guard = clients.lock().await;
+    clients_guard.get(client_id).is_some_and(|c| c.account.is_some())
+}
+
+async fn is_waiting_for(client_id: &str, room_id: &str, clients: &Clients) -> bool {
+    let clients_guard = clients.lock().await;
+    clients_guard.get(client_id).is_some_and(|c| c.waiting_for.as_deref() == Some(room_id))
+}
+
+async fn room_exists(room_id: &str, rooms: &Rooms) -> bool {
+    rooms.read().await.contains_key(room_id)
+}
+
+async fn is_in_room(client_id: &str, clients: &Clients) -> bool {
+    let clients_guard = clients.lock().await;
+    clients_guard.get(client_id).is_some_and(|c| c.room.is_some() || c.waiting_for.is_some())
+}
+
+/// Fills in reaction counts, which the room keeps, on messages from the store.
+async fn add_reactions(room_id: &str, messages: &mut [StoredMessage], rooms: &Rooms) {
+    let rooms_guard = rooms.read().await;
+    if let Some(room) = rooms_guard.get(room_id) {
+        room.add_reactions(messages);
+    }
+}
+
+/// Whether the client is seated or spectating in `room_id` (not waitlisted).
+async fn is_room_member(client_id: &str, room_id: &str, rooms: &Rooms) -> bool {
+    let rooms_guard = rooms.read().await;
+    rooms_guard.get(room_id).is_some_and(|room| {
+        room.clients.iter().chain(&room.spectators).any(|id| id == client_id)
+    })
+}
+
+/// Runs an account-store operation on the blocking thread pool, since SQLite
+/// queries and Argon2 hashing would otherwise stall the async runtime.
+async fn with_accounts<T, F>(accounts: &Accounts, operation: F) -> Result<T, String>
+where
+    T: Send + 'static,
+    F: FnOnce(&AccountStore) -> Result<T, Box<dyn std::error::Error + Send + Sync>> + Send + 'static,
+{
+    let accounts = Arc::clone(accounts);
+    tokio::task::spawn_blocking(move || {
+        let store = accounts.lock().map_err(|_| "account store lock poisoned")?;
+        operation(&store)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+async fn send_history_service_error(
+    error: String,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    eprintln!("Message history error: {}", error);
+    send_message(writer, &Message::Error {
+        message: "Message history is unavailable, please try again later".to_string(),
+        kind: ErrorKind::Other,
+    }).await
+}
+
+async fn send_account_service_error(
+    error: String,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    eprintln!("Account store error: {}", error);
+    send_message(writer, &Message::Error {
+        message: "Account service is unavailable, please try again later".to_string(),
+        kind: ErrorKind::Other,
+    }).await
+}
+
+// ============================================================================
+// Waitlist Handling
+// ============================================================================
+
+async fn join_waitlist(
+    room_id: &str,
+    username: &str,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let queued = {
+        let mut rooms_guard = rooms.write().await;
+        let mut clients_guard = clients.lock().await;
+        let Some(room) = rooms_guard.get_mut(room_id) else {
+            return Ok(());
+        };
+        if is_name_in_use(room, &clients_guard, username, client_id) {
+            None
+        } else {
+            if !room.waitlist.iter().any(|id| id == client_id) {
+                room.waitlist.push_back(client_id.to_string());
+            }
+
+            // Keep the client out of the room so it receives no broadcasts while waiting
+            if let Some(client) = clients_guard.get_mut(client_id) {
+                client.username = username.to_string();
+                client.room = None;
+                client.waiting_for = Some(room_id.to_string());
+                client.spectator = false;
+            }
+            Some((room.name.clone(), room.waitlist.len()))
+        }
+    };
+
+    let Some((room_name, position)) = queued else {
+        return send_username_taken(username, writer).await;
+    };
+
+    println!("User '{}' is waiting for a seat in room '{}' (position {})", username, room_name, position);
+
+    send_message(writer, &Message::Waitlisted { room_name, position }).await?;
+
+    Ok(())
+}
+
+async fn admit_next_waiter(
+    room_id: &str,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        let next_id = {
+            let mut rooms_guard = rooms.write().await;
+            let Some(room) = rooms_guard.get_mut(room_id) else {
+                return Ok(());
+            };
+            if room.clients.len() >= room.max_users {
+                return Ok(());
+            }
+            room.waitlist.pop_front()
+        };
+
+        let Some(next_id) = next_id else {
+            return Ok(());
+        };
+
+        let waiter = {
+            let clients_guard = clients.lock().await;
+            clients_guard.get(&next_id)
+                .filter(|client| client.waiting_for.as_deref() == Some(room_id))
+                .map(|client| (client.username.clone(), Arc::clone(&client.socket)))
+        };
+
+        // The waiter may have disconnected or gone elsewhere since it was queued
+        if let Some((username, socket)) = waiter {
+            let admission = admit_to_room(room_id, &username, &next_id, &socket, clients, rooms).await?;
+            match admission {
+                Admission::Seated => break,
+                // Someone took the seat directly in the meantime
+                Admission::Full => {
+                    let mut rooms_guard = rooms.write().await;
+                    if let Some(room) = rooms_guard.get_mut(room_id) {
+                        room.waitlist.push_front(next_id);
+                    }
+                    break;
+                }
+                // Names are unique across the waitlist too, so this only
+                // happens if a join raced the waiter's; offer the seat on
+                Admission::NameTaken => {
+                    if let Some(client) = clients.lock().await.get_mut(&next_id) {
+                        client.waiting_for = None;
+                    }
+                    send_username_taken(&username, &socket).await?;
+                }
+            }
+        }
+    }
+
+    notify_waitlist_positions(room_id, clients, rooms).await
+}
+
+async fn notify_waitlist_positions(
+    room_id: &str,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let rooms_guard = rooms.read().await;
+    let Some(room) = rooms_guard.get(room_id) else {
+        return Ok(());
+    };
+
+    let clients_guard = clients.lock().await;
+    for (index, waiter_id) in room.waitlist.iter().enumerate() {
+        if let Some(client) = clients_guard.get(waiter_id) {
+            let _ = send_message(&client.socket, &Message::Waitlisted {
+                room_name: room.name.clone(),
+                position: index + 1,
+            }).await;
+        }
+    }
+
+    Ok(())
+}
+
+// ============================================================================
+// Session Resumption
+// ============================================================================
+
+/// Shows a joining client the room's pinned messages, if it has any.
+async fn send_pinned_messages(
+    room_id: &str,
+    pinned: Vec<StoredMessage>,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if pinned.is_empty() {
+        return Ok(());
+    }
+    send_message(writer, &Message::PinnedMessages { room_id: room_id.to_string(), messages: pinned }).await
+}
+
+/// Shows a joining client the room's open polls, if it has any.
+async fn send_open_polls(
+    room_id: &str,
+    polls: Vec<PollSummary>,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if polls.is_empty() {
+        return Ok(());
+    }
+    send_message(writer, &Message::OpenPolls { room_id: room_id.to_string(), polls }).await
+}
+
+async fn send_resume_token(
+    client_id: &str,
+    room_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    send_message(writer, &Message::ResumeToken {
+        token: tokens::sign(client_id, room_id),
+        grace_period_secs: RESUME_GRACE_PERIOD.as_secs(),
+    }).await
+}
+
+/// Moves a held seat onto this connection. The room never sees the client
+/// leave, so nothing is broadcast.
+async fn handle_resume(
+    token: &str,
+    client_id: &str,
+    writer: &Arc<Mutex<tokio::net::tcp::OwnedWriteHalf>>,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let held_client = {
+        let mut clients_guard = clients.lock().await;
+        let already_seated = clients_guard
+            .get(client_id)
+            .is_some_and(|c| c.room.is_some() || c.waiting_for.is_some());
+
+        match tokens::verify(token) {
+            Some((old_id, room_id)) if !already_seated => {
+                let is_held = clients_guard.get(&old_id).is_some_and(|c| {
+                    c.held.is_some() && c.room.as_deref() == Some(room_id.as_str())
+                });
+                if is_held {
+                    clients_guard.remove(&old_id).map(|client| (old_id, room_id, client))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    };
+
+    let Some((old_id, room_id, mut client)) = held_client else {
+        send_message(writer, &Message::Error {
+            message: "Your session has expired and can't be resumed".to_string(),
+            kind: ErrorKind::ResumeFailed,
+        }).await?;
+        return Ok(());
+    };
+
+    // Take over the seat under this connection's ID
+    let room_name = {
+        let mut rooms_guard = rooms.write().await;
+        rooms_guard.get_mut(&room_id).map(|room| {
+            for id in room.clients.iter_mut().chain(room.spectators.iter_mut()) {
+                if *id == old_id {
+                    *id = client_id.to_string();
+                }
+            }
+            if room.owner == old_id {
+                room.owner = client_id.to_string();
+            }
+            room.name.clone()
+        }).unwrap_or_default()
+    };
+
+    let username = client.username.clone();
+    client.held = None;
+    client.socket = Arc::clone(writer);
+    {
+        let mut clients_guard = clients.lock().await;
+        if let Some(current) = clients_guard.get(client_id) {
+            client.addr = current.addr;
+        }
+        clients_guard.insert(client_id.to_string(), client);
+    }
+
+    println!("User '{}' resumed their session in room '{}'", username, room_name);
+
+    send_message(writer, &Message::Resumed { room_name, username }).await?;
+    send_resume_token(client_id, &room_id, writer).await
 }
 
 // ============================================================================
@@ -325,38 +3362,135 @@ async fn handle_disconnect(
     clients: &Clients,
     rooms: &Rooms,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let (room_id, username) = {
+    // Clients with a seat keep it for a while in case they reconnect
+    let hold = {
+        let mut clients_guard = clients.lock().await;
+        match clients_guard.get_mut(client_id) {
+            Some(client) if client.room.is_some() => {
+                let hold_id = Uuid::new_v4();
+                client.held = Some(hold_id);
+                Some((hold_id, client.username.clone()))
+            }
+            _ => None,
+        }
+    };
+
+    let Some((hold_id, username)) = hold else {
+        return remove_client(client_id, clients, rooms).await;
+    };
+
+    println!(
+        "User '{}' disconnected; holding their seat for {}s",
+        username,
+        RESUME_GRACE_PERIOD.as_secs()
+    );
+
+    let (client_id, clients, rooms) = (client_id.to_string(), Arc::clone(clients), Arc::clone(rooms));
+    tokio::spawn(async move {
+        tokio::time::sleep(RESUME_GRACE_PERIOD).await;
+
+        let expired = {
+            let clients_guard = clients.lock().await;
+            clients_guard.get(&client_id).is_some_and(|c| c.held == Some(hold_id))
+        };
+
+        if expired {
+            if let Err(e) = remove_client(&client_id, &clients, &rooms).await {
+                eprintln!("Error releasing held seat: {}", e);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Removes a client for good, announcing the departure and freeing its seat.
+async fn remove_client(
+    client_id: &str,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    leave_room(client_id, clients, rooms).await?;
+    clients.lock().await.remove(client_id);
+    Ok(())
+}
+
+/// Takes a client out of its room or waitlist, announcing the departure and
+/// freeing its seat. The connection itself stays open.
+async fn leave_room(
+    client_id: &str,
+    clients: &Clients,
+    rooms: &Rooms,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (room_id, waiting_for, username, spectator) = {
         let mut clients_guard = clients.lock().await;
-        clients_guard.remove(client_id).map(|client| {
-            (client.room, client.username)
+        clients_guard.get_mut(client_id).map(|client| {
+            (
+                client.room.take(),
+                client.waiting_for.take(),
+                client.username.clone(),
+                std::mem::take(&mut client.spectator),
+            )
         }).unwrap_or_default()
     };
 
+    // Drop out of the waitlist and move everyone behind up a place
+    if let Some(waiting_for) = waiting_for {
+        {
+            let mut rooms_guard = rooms.write().await;
+            if let Some(room) = rooms_guard.get_mut(&waiting_for) {
+                room.waitlist.retain(|id| id != client_id);
+            }
+        }
+        return notify_waitlist_positions(&waiting_for, clients, rooms).await;
+    }
+
     let Some(room_id) = room_id else {
         return Ok(());
     };
 
     // Notify other users
-    if !username.is_empty() {
+    if !username.is_empty() && !spectator {
         let leave_msg = Message::UserLeft { username: username.clone() };
         let _ = broadcast_to_room(clients, rooms, &room_id, &leave_msg, None).await;
     }
 
     // Clean up room
-    let mut rooms_guard = rooms.write().await;
-    if let Some(room) = rooms_guard.get_mut(&room_id) {
+    let has_waiters = {
+        let mut rooms_guard = rooms.write().await;
+        let Some(room) = rooms_guard.get_mut(&room_id) else {
+            return Ok(());
+        };
         room.clients.retain(|id| id != client_id);
+        room.spectators.retain(|id| id != client_id);
+
+        // Ownership passes to the longest-present participant
+        if room.owner == client_id {
+            if let Some(next_owner) = room.clients.first() {
+                room.owner = next_owner.clone();
+            }
+        }
 
         let remaining = room.clients.len();
         let room_name = room.name.clone();
         let max_users = room.max_users;
+        let has_waiters = !room.waitlist.is_empty();
 
-        if remaining == 0 {
-            println!("Room '{}' (ID: {}) is now empty and will be removed", room_name, room_id);
+        if remaining == 0 && room.spectators.is_empty() && !has_waiters {
+            println!("Room '{}' (ID: {}) is now empty and will be removed", room_name, room.id);
             rooms_guard.remove(&room_id);
+        } else if spectator {
+            println!("Spectator '{}' left room '{}'", username, room_name);
         } else {
             println!("User '{}' left room '{}' ({}/{} users remaining)", username, room_name, remaining, max_users);
         }
+
+        has_waiters && !spectator
+    };
+
+    // Hand the free seat to whoever has waited longest
+    if has_waiters {
+        admit_next_waiter(&room_id, clients, rooms).await?;
     }
 
     Ok(())
@@ -377,6 +3511,14 @@ async fn send_message(
     Ok(())
 }
 
+/// Peer address of a connection, for log lines.
+async fn peer_addr(client_id: &str, clients: &Clients) -> String {
+    let clients_guard = clients.lock().await;
+    clients_guard
+        .get(client_id)
+        .map_or_else(|| "unknown address".to_string(), |c| c.addr.to_string())
+}
+
 async fn broadcast_to_room(
     clients: &Clients,
     rooms: &Rooms,
@@ -384,17 +3526,214 @@ async fn broadcast_to_room(
     message: &Message,
     exclude_client: Option<&str>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let member_ids = {
+        let rooms_guard = rooms.read().await;
+        match rooms_guard.get(room_id) {
+            Some(room) => room.clients.iter().chain(&room.spectators).cloned().collect::<Vec<_>>(),
+            None => return Ok(()),
+        }
+    };
+
     let clients_guard = clients.lock().await;
 
-    for (client_id, client) in clients_guard.iter() {
-        if exclude_client.map_or(false, |id| client_id == id) {
+    for client_id in &member_ids {
+        if exclude_client == Some(client_id.as_str()) {
             continue;
         }
 
-        if client.room.as_deref() == Some(room_id) {
-            let _ = send_message(&client.socket, message).await;
+        // Skip stale entries for clients that have since moved to another room
+        match clients_guard.get(client_id) {
+            Some(client) if client.room.as_deref() == Some(room_id) => {
+                let _ = send_message(&client.socket, message).await;
+            }
+            _ => {}
         }
     }
 
     Ok(())
 }
+
+/// Gives a new chat message the room's next sequence number, while holding
+/// the room so numbers are never reused, and keeps it in the room's recent
+/// history. Returns `None` if the room is gone.
+async fn stamp_message(
+    room_id: &str,
+    username: String,
+    author_id: Option<String>,
+    content: String,
+    reply_to: Option<Quote>,
+    ephemeral: bool,
+    rooms: &Rooms,
+) -> Option<StoredMessage> {
+    let mut rooms_guard = rooms.write().await;
+    let room = rooms_guard.get_mut(room_id)?;
+    room.last_seq += 1;
+
+    let stored = StoredMessage {
+        id: Uuid::new_v4().to_string(),
+        seq: room.last_seq,
+        timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        username,
+        author_id,
+        content,
+        edited_at: None,
+        reply_to,
+        reactions: BTreeMap::new(),
+    };
+    // Ephemeral messages are never replayed, so nobody can see them after they expire
+    if !ephemeral {
+        room.remember(ChatRecord { message: stored.clone(), received: Instant::now() });
+    }
+    Some(stored)
+}
+
+/// Appends a new chat message to the history store.
+async fn store_message(room_id: &str, message: &StoredMessage, message_log: &MessageLog) {
+    // A storage failure shouldn't stop the conversation
+    let (log_room_id, log_message) = (room_id.to_string(), message.clone());
+    if let Err(e) = with_message_log(message_log, move |store| store.append(&log_room_id, &log_message)).await {
+        eprintln!("Message history error: {}", e);
+    }
+}
+
+/// Broadcasts a new chat message to its room, resolving `@name` mentions
+/// against the room's members. Each mentioned user gets a copy flagged with
+/// `mentioned` so their client can alert them; the author never does.
+async fn broadcast_chat(
+    sender_id: &str,
+    clients: &Clients,
+    rooms: &Rooms,
+    room_id: &str,
+    message: StoredMessage,
+    expires_at: Option<String>,
+) {
+    let member_ids = {
+        let rooms_guard = rooms.read().await;
+        match rooms_guard.get(room_id) {
+            Some(room) => room.clients.iter().chain(&room.spectators).cloned().collect::<Vec<_>>(),
+            None => return,
+        }
+    };
+
+    let clients_guard = clients.lock().await;
+
+    // Skip stale entries for clients that have since moved to another room
+    let members: Vec<(&String, &Client)> = member_ids.iter()
+        .filter_map(|id| Some((id, clients_guard.get(id)?)))
+        .filter(|(_, client)| client.room.as_deref() == Some(room_id))
+        .collect();
+
+    let mentions = resolve_mentions(&message.content, members.iter().map(|(_, client)| &client.username));
+
+    let chat_msg = user_message(message.clone(), false, mentions.clone(), false, expires_at.clone());
+    let mention_msg = user_message(message, false, mentions.clone(), true, expires_at);
+    for (id, client) in members {
+        let mentioned = id.as_str() != sender_id && mentions.contains(&client.username);
+        let _ = send_message(&client.socket, if mentioned { &mention_msg } else { &chat_msg }).await;
+    }
+}
+
+/// The members mentioned in chat text, each once and spelled as in their
+/// username, in the order they are first mentioned.
+fn resolve_mentions<'a>(content: &str, members: impl Iterator<Item = &'a String>) -> Vec<String> {
+    let names: HashMap<String, &String> = members
+        .map(|username| (username.to_lowercase(), username))
+        .collect();
+    let mut mentions: Vec<String> = Vec::new();
+    for name in mentioned_names(content) {
+        // A trailing full stop usually ends the sentence rather than the name
+        let found = names.get(&name.to_lowercase())
+            .or_else(|| names.get(&name.trim_end_matches('.').to_lowercase()));
+        if let Some(&found) = found {
+            if !mentions.contains(found) {
+                mentions.push(found.clone());
+            }
+        }
+    }
+    mentions
+}
+
+/// Names written as `@name` in chat text. A mention starts at an `@` that
+/// doesn't follow a letter or digit, so email addresses don't count, and
+/// runs over the characters usernames can contain.
+fn mentioned_names(content: &str) -> Vec<&str> {
+    let is_name_char = |c: char| USERNAME_RULES.is_allowed_char(c);
+
+    let mut names = Vec::new();
+    let mut previous = None;
+    for (i, c) in content.char_indices() {
+        if c == '@' && !previous.is_some_and(is_name_char) {
+            let rest = &content[i + 1..];
+            let end = rest.find(|c: char| !is_name_char(c)).unwrap_or(rest.len());
+            if end > 0 {
+                names.push(&rest[..end]);
+            }
+        }
+        previous = Some(c);
+    }
+    names
+}
+
+fn sorted<'a>(names: impl Iterator<Item = &'a String>) -> Vec<String> {
+    let mut names: Vec<String> = names.cloned().collect();
+    names.sort();
+    names
+}
+
+/// Runs a message-store operation on the blocking thread pool, like
+/// `with_accounts`.
+async fn with_message_log<T, F>(message_log: &MessageLog, operation: F) -> Result<T, String>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut dyn MessageStore) -> Result<T, Box<dyn std::error::Error + Send + Sync>> + Send + 'static,
+{
+    let message_log = Arc::clone(message_log);
+    tokio::task::spawn_blocking(move || {
+        let mut store = message_log.lock().map_err(|_| "message store lock poisoned")?;
+        operation(store.as_mut())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mentions(content: &str, members: &[&str]) -> Vec<String> {
+        let members: Vec<String> = members.iter().map(|name| name.to_string()).collect();
+        resolve_mentions(content, members.iter())
+    }
+
+    #[test]
+    fn email_addresses_are_not_mentions() {
+        assert_eq!(mentioned_names("mail bob@example.com or @alice"), vec!["alice"]);
+        assert_eq!(mentioned_names("a@b, 1@c and x_@d"), Vec::<&str>::new());
+        assert_eq!(mentioned_names("(@alice) \"@bob\""), vec!["alice", "bob"]);
+        assert_eq!(mentioned_names("@ alone and @@bob"), vec!["bob"]);
+    }
+
+    #[test]
+    fn punctuation_ends_a_mention() {
+        assert_eq!(mentioned_names("thanks @alice, @bob."), vec!["alice", "bob."]);
+        assert_eq!(mentions("thanks @alice, @bob.", &["alice", "bob"]), vec!["alice", "bob"]);
+        // A full stop is kept when it is part of someone's name
+        assert_eq!(mentions("ping @dr.who.", &["dr.who.", "dr.who"]), vec!["dr.who."]);
+        assert_eq!(mentions("ping @dr.who", &["dr.who."]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn mentions_ignore_case_and_repeats() {
+        assert_eq!(
+            mentions("@ALICE @alice @Bob and @alice again", &["Alice", "bob"]),
+            vec!["Alice", "bob"]
+        );
+    }
+
+    #[test]
+    fn unknown_names_are_not_mentions() {
+        assert_eq!(mentions("@mallory @alice", &["alice"]), vec!["alice"]);
+        assert_eq!(mentions("no mentions here", &["alice"]), Vec::<String>::new());
+    }
+}
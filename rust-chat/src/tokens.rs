@@ -0,0 +1,111 @@
+// ============================================================================
+// Resume Tokens
+// ============================================================================
+//
+// A resume token names the connection and room a client held, signed with
+// HMAC-SHA256 so it can't be forged to take over somebody else's seat. The
+// key is generated at startup; rooms only live in memory, so tokens from a
+// previous run have nothing to resume anyway.
+
+use std::sync::OnceLock;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn signing_key() -> &'static [u8; 32] {
+    static KEY: OnceLock<[u8; 32]> = OnceLock::new();
+    KEY.get_or_init(|| {
+        // Two v4 UUIDs give 244 random bits from the OS generator
+        let mut key = [0u8; 32];
+        key[..16].copy_from_slice(Uuid::new_v4().as_bytes());
+        key[16..].copy_from_slice(Uuid::new_v4().as_bytes());
+        key
+    })
+}
+
+fn mac() -> HmacSha256 {
+    HmacSha256::new_from_slice(signing_key()).expect("HMAC accepts keys of any length")
+}
+
+/// Issues a token for `client_id`'s seat in `room_id`.
+pub fn sign(client_id: &str, room_id: &str) -> String {
+    let payload = format!("{}|{}", client_id, room_id);
+    let mut mac = mac();
+    mac.update(payload.as_bytes());
+    let signature = mac.finalize().into_bytes();
+
+    format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(payload),
+        URL_SAFE_NO_PAD.encode(signature)
+    )
+}
+
+/// Checks a token's signature, returning the client and room IDs it was issued for.
+pub fn verify(token: &str) -> Option<(String, String)> {
+    let (payload, signature) = token.split_once('.')?;
+    let payload = URL_SAFE_NO_PAD.decode(payload).ok()?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+
+    let mut mac = mac();
+    mac.update(&payload);
+    mac.verify_slice(&signature).ok()?;
+
+    let payload = String::from_utf8(payload).ok()?;
+    let (client_id, room_id) = payload.split_once('|')?;
+    Some((client_id.to_string(), room_id.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(token: &str) -> (&str, &str) {
+        token.split_once('.').unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let token = sign("client-1", "room-1");
+        assert_eq!(verify(&token), Some(("client-1".to_string(), "room-1".to_string())));
+    }
+
+    #[test]
+    fn tampered_payload_is_rejected() {
+        let token = sign("client-1", "room-1");
+        let (_, signature) = parts(&token);
+        let forged = format!("{}.{}", URL_SAFE_NO_PAD.encode("client-2|room-1"), signature);
+        assert_eq!(verify(&forged), None);
+    }
+
+    #[test]
+    fn tampered_or_truncated_signature_is_rejected() {
+        let token = sign("client-1", "room-1");
+        let (payload, signature) = parts(&token);
+
+        let genuine = URL_SAFE_NO_PAD.decode(signature).unwrap();
+        let mut flipped = genuine.clone();
+        flipped[0] ^= 1;
+        assert_eq!(verify(&format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(&flipped))), None);
+
+        let truncated = URL_SAFE_NO_PAD.encode(&genuine[..16]);
+        assert_eq!(verify(&format!("{}.{}", payload, truncated)), None);
+        assert_eq!(verify(&format!("{}.", payload)), None);
+    }
+
+    #[test]
+    fn malformed_tokens_are_rejected() {
+        let token = sign("client-1", "room-1");
+        let (payload, signature) = parts(&token);
+
+        assert_eq!(verify(&format!("{}{}", payload, signature)), None, "missing '.'");
+        assert_eq!(verify(&format!("{}!.{}", payload, signature)), None, "invalid base64 payload");
+        assert_eq!(verify(&format!("{}.{}*", payload, signature)), None, "invalid base64 signature");
+        assert_eq!(verify(""), None);
+    }
+}
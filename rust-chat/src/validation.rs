@@ -0,0 +1,438 @@
+// ============================================================================
+// Name Validation
+// ============================================================================
+//
+// Every user-supplied name is echoed to every other client's terminal, so the
+// server only accepts names built from a small, printable character set.
+
+/// Unicode categories a name's characters may come from, approximated with
+/// the character properties in `std`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CharCategory {
+    /// Capital letters from any script.
+    UppercaseLetter,
+    /// Small letters from any script.
+    LowercaseLetter,
+    /// Letters from scripts without case, such as Arabic, Hebrew and Han.
+    OtherLetter,
+    /// Digits and other numerals from any script.
+    Number,
+}
+
+impl CharCategory {
+    fn contains(self, c: char) -> bool {
+        match self {
+            CharCategory::UppercaseLetter => c.is_uppercase(),
+            CharCategory::LowercaseLetter => c.is_lowercase(),
+            CharCategory::OtherLetter => c.is_alphabetic() && !c.is_uppercase() && !c.is_lowercase(),
+            CharCategory::Number => c.is_numeric(),
+        }
+    }
+}
+
+/// Letters and numbers from every script.
+const LETTERS_AND_NUMBERS: &[CharCategory] = &[
+    CharCategory::UppercaseLetter,
+    CharCategory::LowercaseLetter,
+    CharCategory::OtherLetter,
+    CharCategory::Number,
+];
+
+/// Limits applied to one kind of name. Adjust the constants below to change
+/// what the server accepts.
+pub struct NameRules {
+    /// How the name is referred to in error messages.
+    pub label: &'static str,
+    /// Minimum length in characters (not bytes).
+    pub min_len: usize,
+    /// Maximum length in characters (not bytes).
+    pub max_len: usize,
+    /// Whether single spaces are allowed between words.
+    pub allow_spaces: bool,
+    /// Categories of character allowed anywhere in the name.
+    pub allowed_categories: &'static [CharCategory],
+    /// Punctuation allowed in addition to `allowed_categories`.
+    pub allowed_symbols: &'static str,
+    /// Whether all letters must come from one script, so a name can't swap in
+    /// a look-alike letter from another one (Latin "a" for Cyrillic "а").
+    pub single_script: bool,
+    /// Names nobody may use, compared by `skeleton`.
+    pub reserved: &'static [&'static str],
+}
+
+pub const USERNAME_RULES: NameRules = NameRules {
+    label: "Username",
+    min_len: 1,
+    max_len: 32,
+    allow_spaces: false,
+    allowed_categories: LETTERS_AND_NUMBERS,
+    allowed_symbols: "-_.",
+    single_script: true,
+    reserved: &["server", "admin", "administrator", "system", "moderator", "root"],
+};
+
+pub const ROOM_NAME_RULES: NameRules = NameRules {
+    label: "Room name",
+    min_len: 1,
+    max_len: 64,
+    allow_spaces: true,
+    allowed_categories: LETTERS_AND_NUMBERS,
+    allowed_symbols: "-_.,:;!?'&#()+/@",
+    single_script: false,
+    reserved: &[],
+};
+
+impl NameRules {
+    /// Checks `name` against these rules, returning a message suitable for
+    /// sending back to the client on failure.
+    pub fn validate(&self, name: &str) -> Result<(), String> {
+        let len = name.chars().count();
+        if len < self.min_len || len > self.max_len {
+            return Err(format!(
+                "{} must be between {} and {} characters",
+                self.label, self.min_len, self.max_len
+            ));
+        }
+
+        // Anything outside the allowed categories must be explicitly allowed,
+        // which keeps out control and formatting characters
+        if let Some(invalid) = name.chars().find(|&c| !self.is_allowed_char(c)) {
+            return Err(format!(
+                "{} contains a character that isn't allowed: '{}'",
+                self.label,
+                invalid.escape_default()
+            ));
+        }
+
+        if self.allow_spaces && (name.starts_with(' ') || name.ends_with(' ') || name.contains("  ")) {
+            return Err(format!(
+                "{} can't start or end with a space or contain consecutive spaces",
+                self.label
+            ));
+        }
+
+        // Checked first so a look-alike of a reserved name is reported as one
+        let skeleton = skeleton(name);
+        if self.reserved.iter().any(|reserved| skeleton == *reserved) {
+            return Err(format!("{} '{}' is reserved", self.label, name));
+        }
+
+        if self.single_script && !is_single_script(name) {
+            return Err(format!("{} can't mix letters from different alphabets", self.label));
+        }
+
+        Ok(())
+    }
+
+    pub fn is_allowed_char(&self, c: char) -> bool {
+        self.allowed_categories.iter().any(|category| category.contains(c))
+            || (self.allow_spaces && c == ' ')
+            || self.allowed_symbols.contains(c)
+    }
+}
+
+// ============================================================================
+// Look-alike Names
+// ============================================================================
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+    Armenian,
+    Hebrew,
+    Arabic,
+    Devanagari,
+    Thai,
+    Georgian,
+    Hangul,
+    Hiragana,
+    Katakana,
+    Bopomofo,
+    Han,
+    /// Letters from any script not listed above.
+    Other,
+}
+
+/// The script a letter belongs to, by code block. Digits and punctuation are
+/// shared by every script and have none.
+fn script_of(c: char) -> Option<Script> {
+    if !c.is_alphabetic() {
+        return None;
+    }
+    let script = match c as u32 {
+        0x0041..=0x02AF | 0x1E00..=0x1EFF | 0x2C60..=0x2C7F | 0xA720..=0xA7FF | 0xAB30..=0xAB6F
+        | 0xFF21..=0xFF5A => Script::Latin,
+        0x0370..=0x03FF | 0x1F00..=0x1FFF => Script::Greek,
+        0x0400..=0x052F | 0x1C80..=0x1C8F | 0x2DE0..=0x2DFF | 0xA640..=0xA69F => Script::Cyrillic,
+        0x0530..=0x058F => Script::Armenian,
+        0x0590..=0x05FF => Script::Hebrew,
+        0x0600..=0x06FF | 0x0750..=0x077F | 0x08A0..=0x08FF => Script::Arabic,
+        0x0900..=0x097F => Script::Devanagari,
+        0x0E00..=0x0E7F => Script::Thai,
+        0x10A0..=0x10FF | 0x2D00..=0x2D2F => Script::Georgian,
+        0x1100..=0x11FF | 0x3130..=0x318F | 0xAC00..=0xD7AF => Script::Hangul,
+        0x3040..=0x309F => Script::Hiragana,
+        0x30A0..=0x30FF | 0xFF66..=0xFF9F => Script::Katakana,
+        0x3100..=0x312F => Script::Bopomofo,
+        0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xF900..=0xFAFF | 0x20000..=0x2FFFF => Script::Han,
+        _ => Script::Other,
+    };
+    Some(script)
+}
+
+/// Whether every letter in `name` comes from one script. Latin may be mixed
+/// with the scripts that are normally written alongside it: Han with kana,
+/// Bopomofo or Hangul.
+fn is_single_script(name: &str) -> bool {
+    const MIXES: [&[Script]; 3] = [
+        &[Script::Latin, Script::Han, Script::Hiragana, Script::Katakana],
+        &[Script::Latin, Script::Han, Script::Bopomofo],
+        &[Script::Latin, Script::Han, Script::Hangul],
+    ];
+
+    let mut scripts: Vec<Script> = Vec::new();
+    for script in name.chars().filter_map(script_of) {
+        if !scripts.contains(&script) {
+            scripts.push(script);
+        }
+    }
+
+    scripts.len() <= 1 || MIXES.iter().any(|mix| scripts.iter().all(|script| mix.contains(script)))
+}
+
+/// Folds a name for comparison: lowercased, with full-width Latin letters and
+/// the Greek and Cyrillic letters that look like Latin ones replaced by those
+/// Latin letters. Names with the same skeleton can't be told apart on screen.
+pub fn skeleton(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '\u{FF21}'..='\u{FF3A}' | '\u{FF41}'..='\u{FF5A}' => {
+                char::from_u32(c as u32 - 0xFF21 + 0x41).unwrap_or(c)
+            }
+            'А' | 'а' | 'Α' | 'α' => 'a',
+            'В' | 'Β' | 'ϐ' => 'b',
+            'С' | 'с' | 'Ϲ' | 'ϲ' => 'c',
+            'ԁ' => 'd',
+            'Е' | 'е' | 'Ε' => 'e',
+            'Һ' | 'һ' | 'Н' | 'Η' => 'h',
+            'І' | 'і' | 'Ι' | 'ι' | 'Ӏ' | 'ӏ' => 'i',
+            'Ј' | 'ј' => 'j',
+            'К' | 'Κ' | 'κ' => 'k',
+            'М' | 'Μ' => 'm',
+            'Ν' => 'n',
+            'О' | 'о' | 'Ο' | 'ο' | 'σ' => 'o',
+            'Р' | 'р' | 'Ρ' | 'ρ' => 'p',
+            'ԛ' => 'q',
+            'Ѕ' | 'ѕ' => 's',
+            'Т' | 'Τ' => 't',
+            'υ' => 'u',
+            'ν' => 'v',
+            'Ԝ' | 'ԝ' => 'w',
+            'Х' | 'х' | 'Χ' | 'χ' => 'x',
+            'У' | 'у' | 'Υ' | 'γ' => 'y',
+            'Ζ' => 'z',
+            c => c,
+        })
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// ============================================================================
+// Chat Content
+// ============================================================================
+
+/// Cleans chat text before it is broadcast. Line breaks and tabs become
+/// spaces so nobody can fake extra lines from other users, and every other
+/// control or bidirectional-override character is dropped, which defuses
+/// ANSI/OSC escape sequences. Fails if nothing printable is left.
+pub fn sanitize_chat(content: &str) -> Result<String, String> {
+    let sanitized: String = content
+        .chars()
+        .filter_map(|c| match c {
+            '\t' | '\n' | '\r' | '\u{2028}' | '\u{2029}' => Some(' '),
+            c if is_unsafe_char(c) => None,
+            c => Some(c),
+        })
+        .collect();
+
+    if sanitized.trim().is_empty() {
+        return Err("Message is empty".to_string());
+    }
+
+    Ok(sanitized)
+}
+
+/// Longest custom status line, in characters.
+pub const MAX_STATUS_LEN: usize = 64;
+
+/// Cleans a presence status line the same way as chat text. A blank status
+/// clears it.
+pub fn sanitize_status(status: &str) -> Result<Option<String>, String> {
+    let Ok(status) = sanitize_chat(status) else {
+        return Ok(None);
+    };
+
+    let status = status.trim();
+    if status.chars().count() > MAX_STATUS_LEN {
+        return Err(format!("Status must be at most {} characters", MAX_STATUS_LEN));
+    }
+
+    Ok(Some(status.to_string()))
+}
+
+/// Longest history search query, in characters.
+pub const MAX_SEARCH_LEN: usize = 200;
+
+/// Cleans history search terms the same way as chat text.
+pub fn sanitize_search(query: &str) -> Result<String, String> {
+    let query = sanitize_chat(query).map_err(|_| "Search terms can't be empty".to_string())?;
+
+    let query = query.trim();
+    if query.chars().count() > MAX_SEARCH_LEN {
+        return Err(format!("Search terms must be at most {} characters", MAX_SEARCH_LEN));
+    }
+
+    Ok(query.to_string())
+}
+
+/// Longest reaction, in characters. Enough for emoji built from several
+/// code points, or a short text reaction like `+1`.
+pub const MAX_REACTION_LEN: usize = 16;
+
+/// Checks a reaction: a short token with no spaces or control characters.
+pub fn validate_reaction(emoji: &str) -> Result<String, String> {
+    let emoji = emoji.trim();
+    if emoji.is_empty() {
+        return Err("Reaction is empty".to_string());
+    }
+    if emoji.chars().count() > MAX_REACTION_LEN {
+        return Err(format!("Reactions must be at most {} characters", MAX_REACTION_LEN));
+    }
+    if emoji.chars().any(|c| c.is_whitespace() || is_unsafe_char(c)) {
+        return Err("Reactions can't contain spaces or control characters".to_string());
+    }
+    Ok(emoji.to_string())
+}
+
+/// Longest poll question, in characters.
+pub const MAX_POLL_QUESTION_LEN: usize = 200;
+
+/// Longest poll option, in characters.
+pub const MAX_POLL_OPTION_LEN: usize = 80;
+
+/// Most options one poll can offer.
+pub const MAX_POLL_OPTIONS: usize = 10;
+
+/// Cleans a poll's question and options the same way as chat text. A poll
+/// needs at least two options, and no two may be the same ignoring case.
+pub fn sanitize_poll(question: &str, options: &[String]) -> Result<(String, Vec<String>), String> {
+    let question = sanitize_chat(question).map_err(|_| "Poll question can't be empty".to_string())?;
+    let question = question.trim();
+    if question.chars().count() > MAX_POLL_QUESTION_LEN {
+        return Err(format!("Poll question must be at most {} characters", MAX_POLL_QUESTION_LEN));
+    }
+
+    if options.len() < 2 || options.len() > MAX_POLL_OPTIONS {
+        return Err(format!("Polls need between 2 and {} options", MAX_POLL_OPTIONS));
+    }
+
+    let mut sanitized: Vec<String> = Vec::with_capacity(options.len());
+    for option in options {
+        let option = sanitize_chat(option).map_err(|_| "Poll options can't be empty".to_string())?;
+        let option = option.trim();
+        if option.chars().count() > MAX_POLL_OPTION_LEN {
+            return Err(format!("Poll options must be at most {} characters", MAX_POLL_OPTION_LEN));
+        }
+        if sanitized.iter().any(|other| other.to_lowercase() == option.to_lowercase()) {
+            return Err(format!("Poll option '{}' is listed twice", option));
+        }
+        sanitized.push(option.to_string());
+    }
+
+    Ok((question.to_string(), sanitized))
+}
+
+/// Characters a terminal may interpret rather than display: C0/C1 controls
+/// (ESC, CSI, OSC, BEL, ...) and the invisible marks that reorder text.
+fn is_unsafe_char(c: char) -> bool {
+    c.is_control()
+        || matches!(c, '\u{061C}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hostile_payloads::{has_terminal_control, HOSTILE_PAYLOADS};
+
+    fn username_error(name: &str) -> String {
+        USERNAME_RULES.validate(name).unwrap_err()
+    }
+
+    #[test]
+    fn look_alikes_of_reserved_names_are_reserved() {
+        assert!(username_error("admin").contains("reserved"));
+        assert!(username_error("\u{430}dmin").contains("reserved"), "Cyrillic a");
+        assert!(username_error("ＡＤＭＩＮ").contains("reserved"), "full-width Latin");
+        assert!(username_error("Ѕуѕtеm").contains("reserved"), "mostly Cyrillic");
+    }
+
+    #[test]
+    fn usernames_cant_mix_alphabets() {
+        assert!(username_error("p\u{430}ypal").contains("different alphabets"));
+        assert!(username_error("alphaβ").contains("different alphabets"));
+        assert!(USERNAME_RULES.validate("Tom李").is_ok(), "Latin with Han");
+        assert!(USERNAME_RULES.validate("たなか田中").is_ok(), "kana with Han");
+        assert!(USERNAME_RULES.validate("борис").is_ok());
+        // Room names are free text and may mix
+        assert!(ROOM_NAME_RULES.validate("Café Москва").is_ok());
+    }
+
+    #[test]
+    fn usernames_are_limited_to_32_characters() {
+        assert!(USERNAME_RULES.validate(&"a".repeat(32)).is_ok());
+        assert!(username_error(&"a".repeat(33)).contains("between 1 and 32"));
+        // Counted in characters, not bytes
+        assert!(USERNAME_RULES.validate(&"й".repeat(32)).is_ok());
+        assert!(username_error("").contains("between 1 and 32"));
+    }
+
+    #[test]
+    fn usernames_reject_disallowed_characters() {
+        assert!(username_error("bob smith").contains("isn't allowed"));
+        assert!(username_error("bob\u{200b}").contains("isn't allowed"));
+        assert!(USERNAME_RULES.validate("bob_smith-2.0").is_ok());
+    }
+
+    #[test]
+    fn skeleton_folds_look_alike_letters() {
+        assert_eq!(skeleton("pope"), skeleton("роре"));
+        assert_eq!(skeleton("Admin"), "admin");
+        assert_eq!(skeleton("ａｄｍｉｎ"), "admin");
+        assert_ne!(skeleton("pope"), skeleton("rope"));
+    }
+
+    #[test]
+    fn sanitize_chat_leaves_no_terminal_controls() {
+        for payload in HOSTILE_PAYLOADS {
+            if let Ok(sanitized) = sanitize_chat(payload) {
+                assert!(!has_terminal_control(&sanitized), "{:?} became {:?}", payload, sanitized);
+            }
+        }
+    }
+
+    #[test]
+    fn sanitize_chat_keeps_the_visible_text() {
+        assert_eq!(sanitize_chat("\x1b[31mred").unwrap(), "[31mred");
+        assert_eq!(sanitize_chat("hello\nbob: hi").unwrap(), "hello bob: hi");
+        assert_eq!(sanitize_chat("hello\u{2028}bob").unwrap(), "hello bob");
+        assert_eq!(sanitize_chat("\u{202e}exe.txt\u{202c}").unwrap(), "exe.txt");
+    }
+
+    #[test]
+    fn sanitize_chat_rejects_payloads_of_only_controls() {
+        assert!(sanitize_chat("\x1b\x07\u{9b}").is_err());
+        assert!(sanitize_chat("\r\n\t").is_err());
+    }
+}